@@ -50,29 +50,94 @@ const u32 zero = 0;
 // Log utilities
 #define DEBUG(fmt, ...)                                                        \
   ({                                                                           \
-    if (LOG_LVL >= L_DEBUG)                                                    \
+    if (LOG_LVL <= L_DEBUG)                                                    \
       bpf_printk("DEBUG: " fmt, ##__VA_ARGS__);                                \
   })
 #define INFO(fmt, ...)                                                         \
   ({                                                                           \
-    if (LOG_LVL >= L_INFO)                                                     \
+    if (LOG_LVL <= L_INFO)                                                     \
       bpf_printk(BLUE "INFO: " NC fmt, ##__VA_ARGS__);                         \
   })
 #define WARN(fmt, ...)                                                         \
   ({                                                                           \
-    if (LOG_LVL >= L_WARN)                                                     \
+    if (LOG_LVL <= L_WARN)                                                     \
       bpf_printk(YELLOW "WARN: " NC fmt, ##__VA_ARGS__);                       \
   })
 #define ERROR(fmt, ...)                                                        \
   ({                                                                           \
-    if (LOG_LVL >= L_ERROR)                                                    \
+    if (LOG_LVL <= L_ERROR)                                                    \
       bpf_printk(RED "ERROR: " NC fmt, ##__VA_ARGS__);                         \
   })
 
-// Log level. Default is L_DEBUG.
-enum LOG_LEVEL { L_DEBUG = 0, L_INFO, L_WARN, L_ERROR };
+// Log level: messages at or above LOG_LVL are printed; L_OFF disables all of
+// them. Set from userspace before load. Default is L_DEBUG.
+enum LOG_LEVEL { L_DEBUG = 0, L_INFO, L_WARN, L_ERROR, L_OFF };
 const volatile u8 LOG_LVL = L_DEBUG;
 
+// Error counters, so failures that are only logged above still reach
+// userspace. Indices must match BpfErrors in common/src/bpf_errors.rs.
+enum ERROR_COUNTER {
+  // bpf_ringbuf_reserve failed; the window or record was not emitted
+  E_RINGBUF_RESERVE = 0,
+  // Groups left out of a window's results to fit them in the ring buffer
+  E_TRUNCATED,
+  // Inserting a new group into an aggregation map failed, usually because
+  // the map was full; the event was dropped
+  E_MAP_FULL,
+  // Windows with no events, passed over when the next event arrived
+  E_WINDOW_SKIP,
+  E_MAX,
+};
+
+struct {
+  __uint(type, BPF_MAP_TYPE_ARRAY);
+  __type(key, u32);
+  __type(value, u64);
+  __uint(max_entries, E_MAX);
+} error_counts SEC(".maps");
+
+// Adds n to an error counter.
+static __always_inline void count_error(u32 counter, u64 n) {
+  u64 *cnt = bpf_map_lookup_elem(&error_counts, &counter);
+  if (cnt)
+    __sync_fetch_and_add(cnt, n);
+}
+
+// Output transport, set from userspace before load: probes submit to their
+// ring buffer, or with T_PERFBUF to perf_buf. With T_MAPPOLL they only
+// aggregate, and userspace reads and clears their maps. Must match Transport
+// on the Rust side.
+enum TRANSPORT { T_RINGBUF = 0, T_PERFBUF, T_MAPPOLL };
+const volatile u8 TRANSPORT = T_RINGBUF;
+
+// One perf buffer per CPU; libbpf sizes the array to the number of CPUs
+struct {
+  __uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
+  __uint(key_size, sizeof(u32));
+  __uint(value_size, sizeof(u32));
+} perf_buf SEC(".maps");
+
+// Largest sample sent through perf_buf; the size of a perf record is a u16
+#define PERF_SAMPLE_MAX (1 << 15)
+
+// Sends n records of size bytes from buf through perf_buf, in samples of at
+// most PERF_SAMPLE_MAX bytes. buf must be a map value with room for
+// max_samples such samples. Samples the buffer has no room for are counted as
+// lost by the kernel and reported to userspace.
+static __always_inline void perf_output_records(void *ctx, void *buf, u64 size, u64 n,
+                                                u32 max_samples) {
+  u64 per_sample = PERF_SAMPLE_MAX / size;
+  for (u32 i = 0; i < max_samples; i++) {
+    u64 off = i * per_sample;
+    if (off >= n)
+      break;
+    u64 rows = n - off;
+    if (rows > per_sample)
+      rows = per_sample;
+    bpf_perf_event_output(ctx, &perf_buf, BPF_F_CURRENT_CPU, buf + off * size, rows * size);
+  }
+}
+
 /// Common helper accesses.
 #define COMM(str)                                                              \
   do {                                                                         \
@@ -83,21 +148,23 @@ const volatile u8 LOG_LVL = L_DEBUG;
     }                                                                          \
   }  while (0)
 
+// PID is the kernel's (thread) id, in the low 32 bits; TGID is the process id
+// userspace sees, in the high 32 bits.
 #define PID(var)                                                               \
   do {                                                                         \
-    var = bpf_get_current_pid_tgid() >> 32;                                    \
+    var = (u32)bpf_get_current_pid_tgid();                                     \
   } while (0)
 
 #define TGID(var)                                                              \
   do {                                                                         \
-    var = (u32)bpf_get_current_pid_tgid();                                     \
+    var = bpf_get_current_pid_tgid() >> 32;                                    \
   } while (0)
 
 #define PID_TGID(pid, tgid)                                                    \
   do {                                                                         \
     u64 pid_tgid = bpf_get_current_pid_tgid();                                 \
-    pid = pid_tgid >> 32;                                                      \
-    tgid = (u32)pid_tgid;                                                      \
+    pid = (u32)pid_tgid;                                                       \
+    tgid = pid_tgid >> 32;                                                     \
   } while (0)
 
 #define TIME(var) \
@@ -115,6 +182,57 @@ const volatile u8 LOG_LVL = L_DEBUG;
     var = bpf_get_current_cgroup_id(); \
   } while (0)
 
+// Task filters, set from userspace before load; 0 matches every task.
+const volatile u32 FILTER_PID = 0;
+const volatile u32 FILTER_TGID = 0;
+const volatile u64 FILTER_CGROUP = 0;
+
+// Whether the current task passes the task filters. Programs check this
+// before touching any state, so filtered events are invisible to them.
+static __always_inline bool filter_task() {
+  u64 pid_tgid = bpf_get_current_pid_tgid();
+  if (FILTER_PID && (u32)pid_tgid != FILTER_PID)
+    return false;
+  if (FILTER_TGID && (pid_tgid >> 32) != FILTER_TGID)
+    return false;
+  if (FILTER_CGROUP && bpf_get_current_cgroup_id() != FILTER_CGROUP)
+    return false;
+  return true;
+}
+
+// Floor of log2(v); 0 for v == 0.
+static __always_inline u32 log2_u64(u64 v) {
+  u32 r, shift;
+  r = (v > 0xFFFFFFFF) << 5;
+  v >>= r;
+  shift = (v > 0xFFFF) << 4;
+  v >>= shift;
+  r |= shift;
+  shift = (v > 0xFF) << 3;
+  v >>= shift;
+  r |= shift;
+  shift = (v > 0xF) << 2;
+  v >>= shift;
+  r |= shift;
+  shift = (v > 0x3) << 1;
+  v >>= shift;
+  r |= shift;
+  r |= (v >> 1);
+  return r;
+}
+
+// a + b, saturating at U64_MAX like u64::saturating_add on the Rust side, so
+// in-kernel sums match the userspace reference
+static __always_inline u64 sat_add_u64(u64 a, u64 b) {
+  u64 sum = a + b;
+  return sum < a ? ~0ULL : sum;
+}
+
+// v * v, saturating at U64_MAX like u64::saturating_mul
+static __always_inline u64 sat_square_u64(u64 v) {
+  return v > 0xFFFFFFFF ? ~0ULL : v * v;
+}
+
 // Compute the average of two ints (s32s) without overflow.
 static int average_without_overflow(s32 a, s32 b) {
   return (a & b) + ((a ^ b) >> 1);
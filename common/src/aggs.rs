@@ -0,0 +1,138 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use serde::{Serialize, Serializer};
+
+/// Aggregates of the pread query beyond count, max and average, as the bit
+/// mask the BPF programs read from their `AGGS` rodata. The bits match
+/// `enum AGG_FIELD` in `pread_query.bpf.h`. Columns of unselected aggregates
+/// are left zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Aggs(u32);
+
+impl Aggs {
+    pub const DISTINCT: Self = Self(1 << 3);
+    pub const FIELDS: [(&'static str, Self); 5] = [
+        ("min", Self::MIN),
+        ("sum", Self::SUM),
+        ("variance", Self::VARIANCE),
+        ("distinct", Self::DISTINCT),
+        ("hist", Self::HIST),
+    ];
+    pub const HIST: Self = Self(1 << 4);
+    pub const MIN: Self = Self(1 << 0);
+    pub const SUM: Self = Self(1 << 1);
+    pub const VARIANCE: Self = Self(1 << 2);
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// Parses a comma-separated list of aggregates, e.g. `min,hist`; `none` or an
+/// empty string selects none.
+impl FromStr for Aggs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut bits = 0;
+        for name in s.split(',').map(str::trim) {
+            if name.is_empty() || name.eq_ignore_ascii_case("none") {
+                continue;
+            }
+            let (_, agg) = Self::FIELDS
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow!("Unknown aggregate {name}"))?;
+            bits |= agg.0;
+        }
+        Ok(Self(bits))
+    }
+}
+
+impl Display for Aggs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("none");
+        }
+        let names: Vec<_> = Self::FIELDS
+            .iter()
+            .filter(|(_, agg)| self.contains(*agg))
+            .map(|(name, _)| *name)
+            .collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+impl Serialize for Aggs {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Number of log2 buckets of the pread size histogram.
+pub const SIZE_BUCKETS: usize = 32;
+
+/// Bits of the distinct count sketch.
+pub const DISTINCT_BITS: usize = 256;
+
+/// Histogram bucket of `v`: floor of log2, with 0 in bucket 0 and the last
+/// bucket also counting everything above. Matches `log2_u64` in
+/// `common.bpf.h`.
+pub fn size_bucket(v: u64) -> usize {
+    (v.max(1).ilog2() as usize).min(SIZE_BUCKETS - 1)
+}
+
+/// Bit of `v` in the distinct count sketch: the top 8 bits of murmur3's
+/// 64-bit finalizer, as in `ext_agg_add` in `pread_query.bpf.h`.
+pub fn distinct_bit(v: u64) -> usize {
+    let mut h = v;
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^= h >> 33;
+    (h >> 56) as usize
+}
+
+/// Linear counting estimate of the number of distinct values hashed into
+/// `sketch`. Saturates once every bit is set, at a few times
+/// [`DISTINCT_BITS`].
+pub fn distinct_estimate(sketch: &[u64]) -> f64 {
+    let m = (sketch.len() * 64) as f64;
+    let zeros = sketch.iter().map(|w| w.count_zeros()).sum::<u32>().max(1) as f64;
+    m * (m / zeros).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats_aggs() {
+        let aggs: Aggs = "hist,MIN".parse().unwrap();
+        assert_eq!(aggs.to_string(), "min,hist");
+        assert!("none".parse::<Aggs>().unwrap().is_empty());
+        assert!("median".parse::<Aggs>().is_err());
+    }
+
+    #[test]
+    fn estimates_distinct_values() {
+        let mut sketch = [0u64; DISTINCT_BITS / 64];
+        for v in (0..100u64).map(|i| i * 4096) {
+            let bit = distinct_bit(v);
+            sketch[bit / 64] |= 1 << (bit % 64);
+        }
+        let estimate = distinct_estimate(&sketch);
+        assert!((80.0..120.0).contains(&estimate), "estimate {estimate}");
+        assert_eq!(distinct_estimate(&[0; 4]), 0.0);
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt::Display;
+
+use anyhow::{Context, Result};
+use libbpf_rs::{MapFlags, MapHandle};
+use serde::Serialize;
+
+/// Name of the error counter map every probe gets from `common.bpf.h`.
+pub const ERROR_COUNTS_MAP: &str = "error_counts";
+
+/// Failures the BPF programs count in their `error_counts` map instead of only
+/// logging them. Fields are in the order of `enum ERROR_COUNTER` in
+/// `common.bpf.h`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BpfErrors {
+    /// Failed ring buffer reservations; each lost a window or record.
+    pub ringbuf_reserve: u64,
+    /// Groups left out of windows whose results did not fit the ring buffer.
+    pub truncated: u64,
+    /// Failed inserts of new groups into aggregation maps; each lost an event.
+    pub map_full: u64,
+    /// Windows without events that were passed over.
+    pub window_skips: u64,
+}
+
+impl BpfErrors {
+    /// Reads the counters from an `error_counts` map.
+    pub fn read(map: &MapHandle) -> Result<Self> {
+        let mut counters = [0; 4];
+        for (i, counter) in counters.iter_mut().enumerate() {
+            let value = map
+                .lookup(&(i as u32).to_ne_bytes(), MapFlags::ANY)?
+                .with_context(|| format!("{} has no counter {i}", map.name()))?;
+            *counter = u64::from_ne_bytes(value[..8].try_into()?);
+        }
+        let [ringbuf_reserve, truncated, map_full, window_skips] = counters;
+        Ok(Self {
+            ringbuf_reserve,
+            truncated,
+            map_full,
+            window_skips,
+        })
+    }
+
+    /// Counters under their run report names.
+    pub fn counts(&self) -> [(&'static str, u64); 4] {
+        [
+            ("bpf_ringbuf_reserve_failures", self.ringbuf_reserve),
+            ("bpf_truncated_groups", self.truncated),
+            ("bpf_map_full_inserts", self.map_full),
+            ("bpf_window_skips", self.window_skips),
+        ]
+    }
+
+    /// Counts added since `prev`, an earlier reading of the same map.
+    pub fn since(&self, prev: &Self) -> Self {
+        Self {
+            ringbuf_reserve: self.ringbuf_reserve - prev.ringbuf_reserve,
+            truncated: self.truncated - prev.truncated,
+            map_full: self.map_full - prev.map_full,
+            window_skips: self.window_skips - prev.window_skips,
+        }
+    }
+
+    /// Whether any results were lost. Window skips are not counted, as a quiet
+    /// workload skips windows without losing anything.
+    pub fn lost_results(&self) -> bool {
+        self.ringbuf_reserve > 0 || self.truncated > 0 || self.map_full > 0
+    }
+}
+
+impl Display for BpfErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ring buffer reserve failures: {}\ttruncated groups: {}\tmap full inserts: {}\t\
+             window skips: {}",
+            self.ringbuf_reserve, self.truncated, self.map_full, self.window_skips
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_skips_are_not_lost_results() {
+        let prev = BpfErrors {
+            map_full: 2,
+            ..Default::default()
+        };
+        let now = BpfErrors {
+            map_full: 2,
+            window_skips: 3,
+            ..Default::default()
+        };
+        let delta = now.since(&prev);
+        assert_eq!(delta.map_full, 0);
+        assert_eq!(delta.window_skips, 3);
+        assert!(!delta.lost_results());
+        assert!(now.lost_results());
+    }
+}
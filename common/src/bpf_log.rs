@@ -0,0 +1,114 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader},
+    str::FromStr,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::Level;
+use serde::Serialize;
+
+const TRACE_PIPES: &[&str] = &[
+    "/sys/kernel/debug/tracing/trace_pipe",
+    "/sys/kernel/tracing/trace_pipe",
+];
+
+/// Threshold of the logging macros in `common.bpf.h`; the values match its
+/// `enum LOG_LEVEL` and are written to the `LOG_LVL` rodata.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum BpfLogLevel {
+    Debug = 0,
+    Info,
+    Warn,
+    Error,
+    /// Compiles every log statement out of the programs.
+    #[default]
+    Off,
+}
+
+impl BpfLogLevel {
+    pub const NAMES: [&'static str; 5] = ["debug", "info", "warn", "error", "off"];
+}
+
+impl FromStr for BpfLogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "debug" => Ok(Self::Debug),
+            "info" => Ok(Self::Info),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            "off" => Ok(Self::Off),
+            _ => Err(anyhow!("Unknown BPF log level {s}")),
+        }
+    }
+}
+
+impl Display for BpfLogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// Spawns a thread that tails the kernel trace pipe and logs each
+/// `bpf_printk` line through `log` under the `bpf` target, at the level of its
+/// `common.bpf.h` prefix. Reading the trace pipe consumes it, so this competes
+/// with anything else reading it; the thread runs until the process exits.
+pub fn spawn_trace_pipe_reader() -> Result<JoinHandle<()>> {
+    let f = TRACE_PIPES
+        .iter()
+        .find_map(|path| File::open(path).ok())
+        .with_context(|| format!("Failed to open any of {TRACE_PIPES:?}"))?;
+    Ok(thread::spawn(move || {
+        for line in BufReader::new(f).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if let Some((level, msg)) = parse_trace_line(&line) {
+                log::log!(target: "bpf", level, "{msg}");
+            }
+        }
+    }))
+}
+
+/// Splits a trace pipe line into its level and message, dropping the color
+/// codes of the logging macros. Lines without a known prefix (e.g. plain
+/// `bpf_printk`s) are logged at info.
+fn parse_trace_line(line: &str) -> Option<(Level, String)> {
+    let (_, msg) = line.split_once("bpf_trace_printk: ")?;
+    let msg = strip_ansi(msg);
+    for (prefix, level) in [
+        ("DEBUG: ", Level::Debug),
+        ("INFO: ", Level::Info),
+        ("WARN: ", Level::Warn),
+        ("ERROR: ", Level::Error),
+    ] {
+        if let Some(msg) = msg.strip_prefix(prefix) {
+            return Some((level, msg.to_string()));
+        }
+    }
+    Some((Level::Info, msg))
+}
+
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip to the end of the SGR sequence
+            for c in chars.by_ref() {
+                if c == 'm' {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
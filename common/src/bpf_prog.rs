@@ -1,13 +1,18 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering::SeqCst},
-    Arc,
+use std::{
+    io, mem,
+    os::fd::{AsFd, AsRawFd},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use crossbeam::channel::Sender;
+use libbpf_rs::{Map, Program};
+use libbpf_sys::{
+    bpf_map_batch_opts, bpf_map_lookup_and_delete_batch, bpf_prog_test_run_opts, bpf_test_run_opts,
+    BPF_F_TEST_RUN_ON_CPU,
+};
 use log::LevelFilter;
 
-use crate::bpf_structs::FromBytes;
+use crate::bpf_structs::{Decode, FromBytes};
 
 pub fn init_log(level: LevelFilter) {
     log::set_max_level(level);
@@ -25,37 +30,138 @@ pub fn bump_memlock_rlimit() -> Result<()> {
     Ok(())
 }
 
-pub fn create_event_handler<T>(
-    tx: Sender<Vec<T>>,
-    done: Arc<AtomicBool>,
-) -> impl FnMut(&[u8]) -> i32
-where
-    T: FromBytes,
-{
-    let tx = tx.clone();
-    let callback = move |buf: &[u8]| -> i32 {
-        if done.load(SeqCst) {
+/// Decodes each ring buffer sample into records with `decode` and sends them to
+/// `tx`. Once the receiver is gone, samples are dropped.
+pub fn create_event_handler<T>(decode: Decode<T>, tx: Sender<Vec<T>>) -> impl FnMut(&[u8]) -> i32 {
+    move |buf: &[u8]| -> i32 {
+        let mut records = Vec::new();
+        if let Err(e) = decode(buf, &mut records) {
+            eprintln!("Failed to decode records: {e}");
             return 1;
         }
-        if buf.len() % std::mem::size_of::<T>() != 0 {
-            eprintln!(
-                "Received record size ({}) that does not evenly divide record size ({})",
-                buf.len(),
-                std::mem::size_of::<T>(),
-            );
-            return 1;
-        }
-
-        let records = buf
-            .chunks(std::mem::size_of::<T>())
-            .map(|buf| T::from_bytes(buf))
-            .collect::<Vec<_>>();
 
         if let Err(e) = tx.send(records) {
             println!("got error: {e}");
             return 1;
         }
-        return 0;
+        0
+    }
+}
+
+/// Runs a `raw_tp` program once through `BPF_PROG_TEST_RUN`, with `args` as its
+/// context and on `cpu` if given, and returns the program's return value.
+pub fn test_run_raw_tp(prog: &Program, args: &[u64], cpu: Option<u32>) -> io::Result<u32> {
+    let mut opts = bpf_test_run_opts {
+        sz: mem::size_of::<bpf_test_run_opts>() as _,
+        ctx_in: args.as_ptr().cast(),
+        ctx_size_in: mem::size_of_val(args) as u32,
+        ..Default::default()
+    };
+    if let Some(cpu) = cpu {
+        opts.flags = BPF_F_TEST_RUN_ON_CPU;
+        opts.cpu = cpu;
+    }
+    let ret = unsafe { bpf_prog_test_run_opts(prog.as_fd().as_raw_fd(), &mut opts) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(-ret));
+    }
+    Ok(opts.retval)
+}
+
+/// Entries read per `BPF_MAP_LOOKUP_AND_DELETE_BATCH` call when draining a map.
+const DRAIN_BATCH: usize = 1024;
+
+/// Reads and deletes every entry of the hash map `map`, decoding keys as `K`
+/// and values as `V`. Keys shorter than `K`, of maps keyed on a prefix of it,
+/// are zero-padded. Entries added while the map is drained may be left for the
+/// next drain.
+pub fn drain_hash_map<K, V>(map: &Map) -> Result<Vec<(K, V)>>
+where
+    K: FromBytes,
+    V: FromBytes,
+{
+    drain_entries::<K, _>(map, map.value_size() as usize, |key, value| {
+        Ok((K::from_bytes(key)?, V::from_bytes(value)?))
+    })
+}
+
+/// Like [`drain_hash_map`] for per-CPU hash maps: each key comes with its
+/// value on every possible CPU.
+pub fn drain_percpu_hash_map<K, V>(map: &Map) -> Result<Vec<(K, Vec<V>)>>
+where
+    K: FromBytes,
+    V: FromBytes,
+{
+    // The kernel copies out each CPU's value at an 8-byte aligned stride
+    let value_size = map.value_size() as usize;
+    let stride = value_size.next_multiple_of(8);
+    let cpus = libbpf_rs::num_possible_cpus()?;
+    drain_entries::<K, _>(map, stride * cpus, |key, values| {
+        let values = values
+            .chunks_exact(stride)
+            .map(|v| V::from_bytes(&v[..value_size]))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((K::from_bytes(key)?, values))
+    })
+}
+
+/// Drains `map` with `BPF_MAP_LOOKUP_AND_DELETE_BATCH`, handing each key,
+/// zero-padded to `K`, and its `value_size` bytes of value to `decode`.
+fn drain_entries<K, T>(
+    map: &Map,
+    value_size: usize,
+    mut decode: impl FnMut(&[u8], &[u8]) -> Result<T>,
+) -> Result<Vec<T>> {
+    let key_size = map.key_size() as usize;
+    let opts = bpf_map_batch_opts {
+        sz: mem::size_of::<bpf_map_batch_opts>() as _,
+        ..Default::default()
     };
-    callback
+    // Position of the kernel in the map between batches; the hash map uses
+    // a bucket index, but the kernel copies key_size bytes
+    let mut in_batch = vec![0u8; key_size.max(mem::size_of::<u32>())];
+    let mut out_batch = in_batch.clone();
+    let mut first = true;
+    let mut batch = DRAIN_BATCH;
+    let mut entries = Vec::new();
+    let mut key = vec![0u8; key_size.max(mem::size_of::<K>())];
+    loop {
+        let mut keys = vec![0u8; key_size * batch];
+        let mut values = vec![0u8; value_size * batch];
+        let mut count = batch as u32;
+        let ret = unsafe {
+            bpf_map_lookup_and_delete_batch(
+                map.as_fd().as_raw_fd(),
+                if first {
+                    std::ptr::null_mut()
+                } else {
+                    in_batch.as_mut_ptr().cast()
+                },
+                out_batch.as_mut_ptr().cast(),
+                keys.as_mut_ptr().cast(),
+                values.as_mut_ptr().cast(),
+                &mut count,
+                &opts,
+            )
+        };
+        let done = ret == -libc::ENOENT;
+        // A bucket with more entries than fit in the batch
+        if ret == -libc::ENOSPC && count == 0 {
+            batch *= 2;
+            continue;
+        }
+        if ret != 0 && !done {
+            return Err(io::Error::from_raw_os_error(-ret))
+                .with_context(|| format!("Failed to drain {}", map.name()));
+        }
+        for i in 0..count as usize {
+            key[..key_size].copy_from_slice(&keys[i * key_size..(i + 1) * key_size]);
+            entries.push(decode(&key, &values[i * value_size..(i + 1) * value_size])?);
+        }
+        if done {
+            return Ok(entries);
+        }
+        mem::swap(&mut in_batch, &mut out_batch);
+        first = false;
+    }
 }
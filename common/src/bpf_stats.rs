@@ -1,13 +1,28 @@
-use std::{fmt::Display, fs, time::Instant};
+use std::{
+    fmt::Display,
+    fs,
+    io::Write,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::{Context, Result};
-use libbpf_rs::query::ProgInfoIter;
-use libbpf_sys::bpf_enable_stats;
+use anyhow::{anyhow, bail, Context, Result};
+use libbpf_rs::{Map, MapHandle, Program};
+use libbpf_sys::{bpf_enable_stats, bpf_prog_get_info_by_fd, bpf_prog_info};
 use procfs::KernelVersion;
+use serde::Serialize;
+
+use crate::bpf_errors::BpfErrors;
 
 const PROCFS_BPF_STATS_ENABLED: &str = "/proc/sys/kernel/bpf_stats_enabled";
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct BpfProgram {
     pub id: u32,
     pub bpf_type: String,
@@ -16,6 +31,7 @@ pub struct BpfProgram {
     pub run_time_ns: u64,
     pub prev_run_cnt: u64,
     pub run_cnt: u64,
+    #[serde(skip)]
     pub instant: Instant,
     pub period_ns: u128,
     pub recursion_misses: u64,
@@ -36,58 +52,266 @@ impl Display for BpfProgram {
     }
 }
 
-pub fn enable_bpf_stats() -> Result<()> {
+impl BpfProgram {
+    /// Moves the current counters into `prev_*` and records the new ones,
+    /// setting `period_ns` to the time since the last update.
+    fn update(&mut self, info: &bpf_prog_info) {
+        let now = Instant::now();
+        self.prev_runtime_ns = self.run_time_ns;
+        self.prev_run_cnt = self.run_cnt;
+        self.run_time_ns = info.run_time_ns;
+        self.run_cnt = info.run_cnt;
+        self.recursion_misses = info.recursion_misses;
+        self.period_ns = now.duration_since(self.instant).as_nanos();
+        self.instant = now;
+    }
+
+    /// Run time spent in the program during the last period.
+    pub fn interval_run_time_ns(&self) -> u64 {
+        self.run_time_ns - self.prev_runtime_ns
+    }
+
+    /// Events handled by the program during the last period.
+    pub fn interval_run_cnt(&self) -> u64 {
+        self.run_cnt - self.prev_run_cnt
+    }
+
+    pub fn ns_per_event(&self) -> f64 {
+        match self.interval_run_cnt() {
+            0 => 0.,
+            cnt => self.interval_run_time_ns() as f64 / cnt as f64,
+        }
+    }
+
+    pub fn events_per_sec(&self) -> f64 {
+        match self.period_ns {
+            0 => 0.,
+            period => self.interval_run_cnt() as f64 * 1e9 / period as f64,
+        }
+    }
+
+    /// Fraction of a single CPU spent in the program during the last period.
+    pub fn cpu_share(&self) -> f64 {
+        match self.period_ns {
+            0 => 0.,
+            period => self.interval_run_time_ns() as f64 / period as f64,
+        }
+    }
+}
+
+/// Keeps BPF run time stats enabled while alive. Stats enabled through the
+/// `bpf_enable_stats` syscall stay on until the returned fd is closed; stats
+/// enabled through procfs are restored to their previous value on drop.
+#[derive(Debug)]
+pub struct BpfStatsGuard {
+    _fd: Option<OwnedFd>,
+    procfs_prev: Option<String>,
+    already_enabled: bool,
+}
+
+impl BpfStatsGuard {
+    /// Whether stats were already enabled system-wide before this guard.
+    pub fn already_enabled(&self) -> bool {
+        self.already_enabled
+    }
+}
+
+impl Drop for BpfStatsGuard {
+    fn drop(&mut self) {
+        if let Some(prev) = &self.procfs_prev {
+            if let Err(e) = fs::write(PROCFS_BPF_STATS_ENABLED, prev) {
+                log::warn!("Failed to restore {PROCFS_BPF_STATS_ENABLED} to {prev}: {e}");
+            }
+        }
+    }
+}
+
+pub fn enable_bpf_stats() -> Result<BpfStatsGuard> {
     let kernel_version = KernelVersion::current()?;
-    // let _owned_fd: OwnedFd;
     let stats_syscall_supported = kernel_version.ge(&KernelVersion::new(5, 8, 0));
 
     // enable BPF stats via syscall if supported
     // otherwise, enable via procfs
     if stats_syscall_supported {
+        // procfs may be hidden or read-only here; the syscall doesn't need it
+        let already_enabled = fs::read_to_string(PROCFS_BPF_STATS_ENABLED)
+            .ok()
+            .is_some_and(|prev| prev.trim() != "0");
         let fd = unsafe { bpf_enable_stats(libbpf_sys::BPF_STATS_RUN_TIME) };
         if fd < 0 {
-            panic!("Failed to enable BPF stats via syscall");
+            return Err(std::io::Error::from_raw_os_error(-fd))
+                .context("Failed to enable BPF stats via syscall");
         }
-        // _owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(BpfStatsGuard {
+            _fd: Some(unsafe { OwnedFd::from_raw_fd(fd) }),
+            procfs_prev: None,
+            already_enabled,
+        })
     } else {
+        let prev = fs::read_to_string(PROCFS_BPF_STATS_ENABLED)
+            .context(format!("Failed to read {}", PROCFS_BPF_STATS_ENABLED))?;
+        let prev = prev.trim().to_string();
+        let already_enabled = prev != "0";
         fs::write(PROCFS_BPF_STATS_ENABLED, b"1").context(format!(
             "Failed to enable BPF stats via {}",
             PROCFS_BPF_STATS_ENABLED
         ))?;
+        Ok(BpfStatsGuard {
+            _fd: None,
+            procfs_prev: Some(prev),
+            already_enabled,
+        })
     }
+}
 
-    Ok(())
+fn get_prog_info(fd: BorrowedFd<'_>) -> Result<bpf_prog_info> {
+    let mut info = bpf_prog_info::default();
+    let mut len = std::mem::size_of::<bpf_prog_info>() as u32;
+    let ret = unsafe { bpf_prog_get_info_by_fd(fd.as_raw_fd(), &mut info, &mut len) };
+    if ret != 0 {
+        bail!(std::io::Error::from_raw_os_error(-ret));
+    }
+    Ok(info)
 }
 
-pub fn get_bpf_stats() -> Vec<BpfProgram> {
-    let mut progs = Vec::new();
-    let iter = ProgInfoIter::default();
-    for prog in iter {
-        let instant = Instant::now();
+/// Reads the current stats of a loaded program through its fd, so the result
+/// belongs to that program rather than whichever program the kernel lists
+/// first.
+pub fn get_prog_stats(prog: &Program) -> Result<BpfProgram> {
+    let instant = Instant::now();
+    let info = get_prog_info(prog.as_fd())
+        .with_context(|| format!("Failed to get info for program {}", prog.name()))?;
 
-        let prog_name = match prog.name.to_str() {
-            Ok(name) => name.to_string(),
-            Err(_) => continue,
-        };
-        if prog_name.is_empty() {
-            continue;
+    Ok(BpfProgram {
+        id: info.id,
+        bpf_type: prog.prog_type().to_string(),
+        name: prog.name().to_string(),
+        prev_runtime_ns: 0,
+        run_time_ns: info.run_time_ns,
+        prev_run_cnt: 0,
+        run_cnt: info.run_cnt,
+        instant,
+        period_ns: 0,
+        recursion_misses: info.recursion_misses,
+    })
+}
+
+/// Stats for each of `progs`, e.g. the programs of a probe's skeleton. Programs
+/// that were not loaded (autoload disabled) are skipped.
+pub fn get_probe_stats<'a>(
+    progs: impl IntoIterator<Item = &'a Program>,
+) -> Result<Vec<BpfProgram>> {
+    progs
+        .into_iter()
+        .filter(|prog| prog.autoload())
+        .map(get_prog_stats)
+        .collect()
+}
+
+/// Background thread that periodically samples a set of programs and appends
+/// per-interval run time, event rate and CPU share to a CSV file, along with
+/// the errors the probe counted in the interval.
+pub struct BpfStatsSampler {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<Result<()>>,
+}
+
+impl BpfStatsSampler {
+    /// Starts sampling `progs`, and the probe's `error_counts` map if given,
+    /// every `interval`. The program and map fds are duplicated, so the sampler
+    /// does not borrow the skeleton.
+    pub fn spawn<'a>(
+        progs: impl IntoIterator<Item = &'a Program>,
+        errors: Option<&Map>,
+        interval: Duration,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let mut sampled = Vec::new();
+        for prog in progs.into_iter().filter(|prog| prog.autoload()) {
+            let fd = prog.as_fd().try_clone_to_owned()?;
+            sampled.push((fd, get_prog_stats(prog)?));
         }
-        let bpf_type = prog.ty.to_string();
-        // println!("Program name: {prog_name}\ttype: {bpf_type}");
-
-        let bpf_program = BpfProgram {
-            id: prog.id,
-            bpf_type,
-            name: prog_name,
-            prev_runtime_ns: 0,
-            run_time_ns: prog.run_time_ns,
-            prev_run_cnt: 0,
-            run_cnt: prog.run_cnt,
-            instant,
-            period_ns: 0,
-            recursion_misses: prog.recursion_misses,
+        let mut errors = match errors {
+            Some(map) => {
+                let map = MapHandle::try_clone(map)?;
+                let prev = BpfErrors::read(&map)?;
+                Some((map, prev))
+            }
+            None => None,
         };
-        progs.push(bpf_program);
+        let path = path.as_ref();
+        let mut f = fs::File::create(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        writeln!(
+            f,
+            "second,timestamp_ms,program,id,run_time_ns,run_cnt,ns_per_event,events_per_sec,cpu_share,\
+             ringbuf_reserve_failures,truncated_groups,map_full_inserts,window_skips"
+        )?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_sampler = stop.clone();
+        let handle = thread::spawn(move || -> Result<()> {
+            let start = Instant::now();
+            while !stop_sampler.load(SeqCst) {
+                thread::sleep(interval);
+                sample(&mut f, start.elapsed(), &mut sampled, errors.as_mut())?;
+            }
+            Ok(())
+        });
+
+        Ok(Self { stop, handle })
     }
-    progs
+
+    pub fn stop(self) -> Result<()> {
+        self.stop.store(true, SeqCst);
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("BPF stats sampler panicked"))?
+    }
+}
+
+/// Writes a row per program, `elapsed` since sampling started in the `second`
+/// column. The error counts are the probe's, so every row of a sample repeats
+/// them.
+fn sample(
+    f: &mut fs::File,
+    elapsed: Duration,
+    progs: &mut [(OwnedFd, BpfProgram)],
+    errors: Option<&mut (MapHandle, BpfErrors)>,
+) -> Result<()> {
+    let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let second = elapsed.as_secs_f64();
+    let interval_errors = match errors {
+        Some((map, prev)) => {
+            let now = BpfErrors::read(map)?;
+            let interval = now.since(prev);
+            *prev = now;
+            if interval.lost_results() {
+                log::warn!("BPF errors in the sample at {second:.3}s: {interval}");
+            }
+            interval
+        }
+        None => BpfErrors::default(),
+    };
+    for (fd, prog) in progs {
+        prog.update(&get_prog_info(fd.as_fd())?);
+        writeln!(
+            f,
+            "{:.3},{},{},{},{},{},{:.1},{:.1},{:.6},{},{},{},{}",
+            second,
+            timestamp_ms,
+            prog.name,
+            prog.id,
+            prog.interval_run_time_ns(),
+            prog.interval_run_cnt(),
+            prog.ns_per_event(),
+            prog.events_per_sec(),
+            prog.cpu_share(),
+            interval_errors.ringbuf_reserve,
+            interval_errors.truncated,
+            interval_errors.map_full,
+            interval_errors.window_skips,
+        )?;
+    }
+    Ok(())
 }
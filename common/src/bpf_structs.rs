@@ -1,10 +1,160 @@
-use std::fmt::Display;
+use std::{fmt::Display, mem};
 
-pub trait FromBytes {
-    fn from_bytes(buf: &[u8]) -> Self;
+use zerocopy::{AsBytes, LayoutVerified};
+
+use crate::{
+    aggs::{self, Aggs, DISTINCT_BITS, SIZE_BUCKETS},
+    group_by::GroupBy,
+};
+
+/// Why a buffer could not be decoded into records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Buffer is shorter than a single record.
+    Truncated { expected: usize, actual: usize },
+    /// Buffer length is not a multiple of the record size.
+    Length { record_size: usize, actual: usize },
+    /// Buffer is not aligned for a borrowed view of the records.
+    Misaligned { align: usize, addr: usize },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated { expected, actual } => {
+                write!(
+                    f,
+                    "truncated record: expected {expected} bytes, got {actual}"
+                )
+            }
+            Self::Length {
+                record_size,
+                actual,
+            } => {
+                write!(
+                    f,
+                    "buffer size ({actual}) does not evenly divide record size ({record_size})"
+                )
+            }
+            Self::Misaligned { align, addr } => {
+                write!(f, "buffer at {addr:#x} is not {align}-byte aligned")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Fallible decoding of records submitted by BPF programs. Implemented for
+/// every type deriving `zerocopy::FromBytes`, which guarantees that any bit
+/// pattern is a valid value.
+pub trait FromBytes: Sized {
+    /// Decodes a single record; `buf` must be exactly one record long. `buf`
+    /// need not be aligned.
+    fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError>;
+
+    /// Borrows `buf` as a slice of records without copying.
+    fn slice_from_bytes(buf: &[u8]) -> Result<&[Self], DecodeError>;
+
+    /// Decodes every record in `buf`, borrowing when `buf` is aligned and
+    /// copying record by record when it is not.
+    fn vec_from_bytes(buf: &[u8]) -> Result<Vec<Self>, DecodeError> {
+        let mut records = Vec::new();
+        Self::extend_from_bytes(buf, &mut records)?;
+        Ok(records)
+    }
+
+    /// Like [`FromBytes::vec_from_bytes`], but appends to `records`, so a
+    /// buffer can be reused across samples. On error, `records` may hold some
+    /// of `buf`'s records.
+    fn extend_from_bytes(buf: &[u8], records: &mut Vec<Self>) -> Result<(), DecodeError>;
+}
+
+impl<T> FromBytes for T
+where
+    T: zerocopy::FromBytes + Copy,
+{
+    fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError> {
+        check_len::<T>(buf)?;
+        if buf.len() != mem::size_of::<T>() {
+            return Err(DecodeError::Length {
+                record_size: mem::size_of::<T>(),
+                actual: buf.len(),
+            });
+        }
+        Ok(T::read_from(buf).expect("length checked above"))
+    }
+
+    fn slice_from_bytes(buf: &[u8]) -> Result<&[Self], DecodeError> {
+        check_len::<T>(buf)?;
+        let addr = buf.as_ptr() as usize;
+        if !addr.is_multiple_of(mem::align_of::<T>()) {
+            return Err(DecodeError::Misaligned {
+                align: mem::align_of::<T>(),
+                addr,
+            });
+        }
+        Ok(LayoutVerified::<_, [T]>::new_slice(buf)
+            .expect("length and alignment checked above")
+            .into_slice())
+    }
+
+    fn extend_from_bytes(buf: &[u8], records: &mut Vec<Self>) -> Result<(), DecodeError> {
+        match Self::slice_from_bytes(buf) {
+            Ok(slice) => records.extend_from_slice(slice),
+            Err(DecodeError::Misaligned { .. }) => {
+                records.reserve(buf.len() / mem::size_of::<T>());
+                for chunk in buf.chunks_exact(mem::size_of::<T>()) {
+                    records.push(Self::from_bytes(chunk)?);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+}
+
+/// Appends the records of a sample to a batch.
+pub type Decode<T> = fn(&[u8], &mut Vec<T>) -> Result<(), DecodeError>;
+
+/// How the samples of a probe decode into records. By default records are sent
+/// as they are and decode with [`FromBytes::extend_from_bytes`].
+pub struct Decoder<T> {
+    /// Size of a record as sent; samples hold a whole number of them.
+    pub record_size: usize,
+    pub decode: Decode<T>,
+}
+
+impl<T: FromBytes> Default for Decoder<T> {
+    fn default() -> Self {
+        Self {
+            record_size: mem::size_of::<T>(),
+            decode: T::extend_from_bytes,
+        }
+    }
+}
+
+fn check_len<T>(buf: &[u8]) -> Result<(), DecodeError> {
+    let record_size = mem::size_of::<T>();
+    if buf.len() < record_size {
+        return Err(DecodeError::Truncated {
+            expected: record_size,
+            actual: buf.len(),
+        });
+    }
+    if !buf.len().is_multiple_of(record_size) {
+        return Err(DecodeError::Length {
+            record_size,
+            actual: buf.len(),
+        });
+    }
+    Ok(())
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+/// Result row of the pread query. Group by fields not selected with
+/// [`GroupBy`](crate::group_by::GroupBy) and aggregates not selected with
+/// [`Aggs`](crate::aggs::Aggs) are zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, zerocopy::FromBytes, AsBytes)]
 #[repr(C)]
 pub struct PreadQueryRecord {
     pub fd: u64,
@@ -12,6 +162,204 @@ pub struct PreadQueryRecord {
     pub count: u64,
     pub max_count: u64,
     pub avg_count: u64,
+    /// Start of the window (or pane) the row belongs to, in ns of the probe's
+    /// clock. Zero for windows closed in userspace.
+    pub window_start: u64,
+    pub tid: u64,
+    pub pid: u64,
+    pub cgroup: u64,
+    /// NUL-padded task name.
+    pub comm: [u8; 16],
+    pub min_count: u64,
+    pub sum_count: u64,
+    /// Sum of squared pread sizes, for the variance.
+    pub sum_sq_count: u64,
+    /// Bitmap of hashed pread sizes; see [`aggs::distinct_estimate`].
+    pub distinct_sketch: [u64; DISTINCT_BITS / 64],
+    /// Bucket `i` counts pread sizes in `[2^i, 2^(i+1))` bytes.
+    pub count_hist: [u64; SIZE_BUCKETS],
+}
+
+/// Result row sent by the ebql and opt probes, `pread_query_t` in
+/// `pread_query.bpf.h`, unless the run selects wide rows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, zerocopy::FromBytes, AsBytes)]
+#[repr(C)]
+pub struct PreadQueryRow {
+    pub fd: u64,
+    pub cpu: u64,
+    pub count: u64,
+    pub max_count: u64,
+    pub avg_count: u64,
+    pub window_start: u64,
+}
+
+/// Result row with the other group by fields and the optional aggregates,
+/// `pread_query_wide_t` in `pread_query.bpf.h`. Only sent if any of them is
+/// selected; see [`PreadQueryRecord::wide_rows`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, zerocopy::FromBytes, AsBytes)]
+#[repr(C)]
+pub struct PreadQueryWideRow {
+    pub row: PreadQueryRow,
+    pub tid: u64,
+    pub pid: u64,
+    pub cgroup: u64,
+    pub comm: [u8; 16],
+    pub min_count: u64,
+    pub sum_count: u64,
+    pub sum_sq_count: u64,
+    pub distinct_sketch: [u64; DISTINCT_BITS / 64],
+    pub count_hist: [u64; SIZE_BUCKETS],
+}
+
+impl From<PreadQueryRow> for PreadQueryRecord {
+    fn from(r: PreadQueryRow) -> Self {
+        Self {
+            fd: r.fd,
+            cpu: r.cpu,
+            count: r.count,
+            max_count: r.max_count,
+            avg_count: r.avg_count,
+            window_start: r.window_start,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<PreadQueryWideRow> for PreadQueryRecord {
+    fn from(r: PreadQueryWideRow) -> Self {
+        Self {
+            tid: r.tid,
+            pid: r.pid,
+            cgroup: r.cgroup,
+            comm: r.comm,
+            min_count: r.min_count,
+            sum_count: r.sum_count,
+            sum_sq_count: r.sum_sq_count,
+            distinct_sketch: r.distinct_sketch,
+            count_hist: r.count_hist,
+            ..r.row.into()
+        }
+    }
+}
+
+/// Decodes rows of `R` into records.
+fn extend_from_rows<R>(buf: &[u8], records: &mut Vec<PreadQueryRecord>) -> Result<(), DecodeError>
+where
+    R: FromBytes + Into<PreadQueryRecord>,
+{
+    check_len::<R>(buf)?;
+    records.reserve(buf.len() / mem::size_of::<R>());
+    for chunk in buf.chunks_exact(mem::size_of::<R>()) {
+        records.push(R::from_bytes(chunk)?.into());
+    }
+    Ok(())
+}
+
+/// Group by fields of a [`PreadQueryRecord`]: fd, cpu, tid, pid, cgroup and
+/// comm.
+pub type PreadGroupKey = (u64, u64, u64, u64, u64, [u8; 16]);
+
+impl PreadQueryRecord {
+    /// Whether the ebql and opt probes send [`PreadQueryWideRow`]s rather than
+    /// [`PreadQueryRow`]s: if any group by field beyond fd and cpu or any
+    /// optional aggregate is selected.
+    pub fn wide_rows(group_by: GroupBy, aggs: Aggs) -> bool {
+        group_by.bits() & !(GroupBy::FD.bits() | GroupBy::CPU.bits()) != 0 || !aggs.is_empty()
+    }
+
+    /// Decoder of the rows the ebql and opt probes send. Its record size is
+    /// their `ROW_BYTES` in `pread_query.bpf.h`.
+    pub fn decoder(wide: bool) -> Decoder<Self> {
+        if wide {
+            Decoder {
+                record_size: mem::size_of::<PreadQueryWideRow>(),
+                decode: extend_from_rows::<PreadQueryWideRow>,
+            }
+        } else {
+            Decoder {
+                record_size: mem::size_of::<PreadQueryRow>(),
+                decode: extend_from_rows::<PreadQueryRow>,
+            }
+        }
+    }
+
+    pub fn group_key(&self) -> PreadGroupKey {
+        (
+            self.fd,
+            self.cpu,
+            self.tid,
+            self.pid,
+            self.cgroup,
+            self.comm,
+        )
+    }
+
+    /// Fills in the optional aggregates selected in `selected` from `agg`, as
+    /// `set_ext_agg` in `pread_query.bpf.h` does in the kernel.
+    pub fn set_ext_agg(&mut self, agg: &ExtAgg, selected: Aggs) {
+        if selected.contains(Aggs::MIN) {
+            self.min_count = agg.min;
+        }
+        if selected.contains(Aggs::SUM) || selected.contains(Aggs::VARIANCE) {
+            self.sum_count = agg.sum;
+        }
+        if selected.contains(Aggs::VARIANCE) {
+            self.sum_sq_count = agg.sum_sq;
+        }
+        self.distinct_sketch = agg.distinct_sketch;
+        self.count_hist = agg.hist;
+    }
+
+    pub fn comm(&self) -> String {
+        let len = self
+            .comm
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.comm.len());
+        String::from_utf8_lossy(&self.comm[..len]).into_owned()
+    }
+
+    /// Population variance of the pread size, from the sum and sum of
+    /// squares.
+    pub fn variance(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let n = self.count as f64;
+        let mean = self.sum_count as f64 / n;
+        (self.sum_sq_count as f64 / n - mean * mean).max(0.0)
+    }
+
+    pub fn distinct_estimate(&self) -> f64 {
+        aggs::distinct_estimate(&self.distinct_sketch)
+    }
+
+    /// Merges `other`'s aggregates into this row, e.g. to total a run. The
+    /// group by fields are kept.
+    pub fn merge(&mut self, other: &PreadQueryRecord) {
+        if other.count == 0 {
+            return;
+        }
+        // count * avg overflows u64 for large preads over long runs
+        let sum = self.count as u128 * self.avg_count as u128
+            + other.count as u128 * other.avg_count as u128;
+        self.min_count = if self.count == 0 {
+            other.min_count
+        } else {
+            self.min_count.min(other.min_count)
+        };
+        self.count += other.count;
+        self.max_count = self.max_count.max(other.max_count);
+        self.avg_count = (sum / self.count as u128) as u64;
+        self.sum_count = self.sum_count.saturating_add(other.sum_count);
+        self.sum_sq_count = self.sum_sq_count.saturating_add(other.sum_sq_count);
+        for (a, b) in self.distinct_sketch.iter_mut().zip(other.distinct_sketch) {
+            *a |= b;
+        }
+        for (a, b) in self.count_hist.iter_mut().zip(other.count_hist) {
+            *a += b;
+        }
+    }
 }
 
 impl Display for PreadQueryRecord {
@@ -24,17 +372,106 @@ impl Display for PreadQueryRecord {
     }
 }
 
-impl FromBytes for PreadQueryRecord {
-    fn from_bytes(buf: &[u8]) -> Self {
-        unsafe {
-            std::mem::transmute::<[u8; std::mem::size_of::<PreadQueryRecord>()], PreadQueryRecord>(
-                buf.try_into().unwrap(),
-            )
+/// Key of the percpu probe's aggregation map: the pread's window, counted on a
+/// grid of the window length from the first pread, and its group by fields.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, zerocopy::FromBytes, AsBytes)]
+#[repr(C)]
+pub struct PercpuPreadKey {
+    pub window: u64,
+    pub gb: PreadGroupBy,
+}
+
+/// State of a group's optional aggregates, `ext_agg_t` in `pread_query.bpf.h`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, zerocopy::FromBytes, AsBytes)]
+#[repr(C)]
+pub struct ExtAgg {
+    pub count: u64,
+    pub min: u64,
+    pub sum: u64,
+    pub sum_sq: u64,
+    pub distinct_sketch: [u64; DISTINCT_BITS / 64],
+    pub hist: [u64; SIZE_BUCKETS],
+}
+
+/// Key of the ebql probe's aggregation maps, `group_by_pread_query_t` in
+/// `pread_query.bpf.h`. Fields not selected in the group by are zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, zerocopy::FromBytes, AsBytes)]
+#[repr(C)]
+pub struct PreadGroupBy {
+    pub fd: u64,
+    pub cpu: u64,
+    pub tid: u64,
+    pub pid: u64,
+    pub cgroup: u64,
+    pub comm: [u8; 16],
+}
+
+impl PreadGroupBy {
+    /// A row of this group with every aggregate zero.
+    pub fn record(&self) -> PreadQueryRecord {
+        PreadQueryRecord {
+            fd: self.fd,
+            cpu: self.cpu,
+            tid: self.tid,
+            pid: self.pid,
+            cgroup: self.cgroup,
+            comm: self.comm,
+            ..Default::default()
+        }
+    }
+}
+
+/// Value of the ebql probe's average map, `avg_t` in `agg_pread_query.bpf.h`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, zerocopy::FromBytes, AsBytes)]
+#[repr(C)]
+pub struct AvgAgg {
+    pub val: u64,
+    pub count: u64,
+}
+
+/// One CPU's aggregates of a group in the percpu probe's map. Count, min and
+/// sum are always kept in `ext`; its other aggregates only if selected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, zerocopy::FromBytes, AsBytes)]
+#[repr(C)]
+pub struct PercpuPreadAgg {
+    pub max: u64,
+    pub ext: ExtAgg,
+}
+
+impl PercpuPreadKey {
+    /// Merges the per-CPU aggregates of this group into its result row. As in
+    /// the other probes' rows, aggregates not selected in `selected` are zero.
+    pub fn record(&self, aggs: &[PercpuPreadAgg], selected: Aggs) -> PreadQueryRecord {
+        let mut r = self.gb.record();
+        let mut min = u64::MAX;
+        for agg in aggs.iter().filter(|a| a.ext.count > 0) {
+            r.count += agg.ext.count;
+            r.max_count = r.max_count.max(agg.max);
+            r.sum_count = r.sum_count.saturating_add(agg.ext.sum);
+            r.sum_sq_count = r.sum_sq_count.saturating_add(agg.ext.sum_sq);
+            min = min.min(agg.ext.min);
+            for (a, b) in r.distinct_sketch.iter_mut().zip(agg.ext.distinct_sketch) {
+                *a |= b;
+            }
+            for (a, b) in r.count_hist.iter_mut().zip(agg.ext.hist) {
+                *a += b;
+            }
+        }
+        if r.count == 0 {
+            return r;
+        }
+        r.avg_count = r.sum_count / r.count;
+        if selected.contains(Aggs::MIN) {
+            r.min_count = min;
+        }
+        if !selected.contains(Aggs::SUM) && !selected.contains(Aggs::VARIANCE) {
+            r.sum_count = 0;
         }
+        r
     }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, zerocopy::FromBytes, AsBytes)]
 #[repr(C)]
 pub struct RawPreadRecord {
     pub time: u64,
@@ -53,12 +490,235 @@ impl Display for RawPreadRecord {
     }
 }
 
-impl FromBytes for RawPreadRecord {
-    fn from_bytes(buf: &[u8]) -> Self {
-        unsafe {
-            std::mem::transmute::<[u8; std::mem::size_of::<RawPreadRecord>()], RawPreadRecord>(
-                buf.try_into().unwrap(),
-            )
-        }
+/// Per-(syscall, fd, cpu) window of the syscall probe. `syscall` is a
+/// [`Syscall`](crate::syscall::Syscall) id; `count` and its max and average are
+/// the byte count argument, or zero for syscalls without one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, zerocopy::FromBytes, AsBytes)]
+#[repr(C)]
+pub struct SyscallQueryRecord {
+    pub syscall: u64,
+    pub fd: u64,
+    pub cpu: u64,
+    pub count: u64,
+    pub max_count: u64,
+    pub avg_count: u64,
+}
+
+impl Display for SyscallQueryRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Record({}, {}, {}, {}, {}, {})",
+            self.syscall, self.fd, self.cpu, self.count, self.max_count, self.avg_count
+        )
+    }
+}
+
+/// Number of log2 latency buckets in [`PreadLatencyRecord`].
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// Per-(fd, cpu) window of the latency probe, which pairs pread entries with
+/// their exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, zerocopy::FromBytes, AsBytes)]
+#[repr(C)]
+pub struct PreadLatencyRecord {
+    pub fd: u64,
+    pub cpu: u64,
+    pub count: u64,
+    /// Preads that returned an error; they add no bytes.
+    pub errors: u64,
+    pub bytes: u64,
+    pub latency_sum_ns: u64,
+    pub latency_max_ns: u64,
+    /// Bucket `i` counts latencies in `[2^i, 2^(i+1))` ns; the last bucket
+    /// also counts everything above.
+    pub latency_hist: [u64; LATENCY_BUCKETS],
+}
+
+impl Display for PreadLatencyRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Record({}, {}, {}, {}, {}, {}, {})",
+            self.fd,
+            self.cpu,
+            self.count,
+            self.errors,
+            self.bytes,
+            self.latency_sum_ns,
+            self.latency_max_ns
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<RawPreadRecord> {
+        (0..4)
+            .map(|i| {
+                RawPreadRecord {
+                    time: i,
+                    fd: i + 1,
+                    cpu: i + 2,
+                    count: i + 3,
+                }
+            })
+            .collect()
+    }
+
+    /// Copies `bytes` into a buffer whose start is `offset` bytes past an
+    /// 8-byte boundary.
+    fn with_offset(bytes: &[u8], offset: usize) -> (Vec<u64>, usize) {
+        let mut backing = vec![0u64; bytes.len() / 8 + 2];
+        let dst = &mut backing.as_mut_slice().as_bytes_mut()[offset..];
+        dst[..bytes.len()].copy_from_slice(bytes);
+        (backing, offset)
+    }
+
+    #[test]
+    fn decodes_single_record() {
+        let r = records()[1];
+        assert_eq!(RawPreadRecord::from_bytes(r.as_bytes()), Ok(r));
+    }
+
+    #[test]
+    fn decodes_aligned_slice_without_copying() {
+        let rs = records();
+        let bytes = rs.as_slice().as_bytes();
+        let view = RawPreadRecord::slice_from_bytes(bytes).unwrap();
+        assert_eq!(view, rs.as_slice());
+        assert_eq!(view.as_ptr() as usize, bytes.as_ptr() as usize);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let r = records()[0];
+        let bytes = &r.as_bytes()[..20];
+        let err = DecodeError::Truncated {
+            expected: 32,
+            actual: 20,
+        };
+        assert_eq!(RawPreadRecord::from_bytes(bytes), Err(err));
+        assert_eq!(RawPreadRecord::slice_from_bytes(bytes), Err(err));
+        assert_eq!(
+            RawPreadRecord::vec_from_bytes(&[]),
+            Err(DecodeError::Truncated {
+                expected: 32,
+                actual: 0
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_partial_trailing_record() {
+        let rs = records();
+        let bytes = &rs.as_slice().as_bytes()[..70];
+        let err = DecodeError::Length {
+            record_size: 32,
+            actual: 70,
+        };
+        assert_eq!(RawPreadRecord::vec_from_bytes(bytes), Err(err));
+        assert_eq!(
+            RawPreadRecord::from_bytes(&bytes[..64]),
+            Err(DecodeError::Length {
+                record_size: 32,
+                actual: 64
+            })
+        );
+    }
+
+    #[test]
+    fn misaligned_input_is_copied_not_borrowed() {
+        let rs = records();
+        let bytes = rs.as_slice().as_bytes();
+        let (backing, offset) = with_offset(bytes, 4);
+        let buf = &backing.as_slice().as_bytes()[offset..offset + bytes.len()];
+
+        assert!(matches!(
+            RawPreadRecord::slice_from_bytes(buf),
+            Err(DecodeError::Misaligned { align: 8, .. })
+        ));
+        assert_eq!(RawPreadRecord::vec_from_bytes(buf).unwrap(), rs);
+        assert_eq!(RawPreadRecord::from_bytes(&buf[32..64]), Ok(rs[1]));
+    }
+
+    #[test]
+    fn decodes_narrow_and_wide_rows() {
+        let row = PreadQueryRow {
+            fd: 3,
+            cpu: 1,
+            count: 2,
+            max_count: 8,
+            avg_count: 6,
+            window_start: 100,
+        };
+        let wide = PreadQueryWideRow {
+            row,
+            pid: 42,
+            sum_count: 12,
+            ..Default::default()
+        };
+
+        let (narrow_decoder, wide_decoder) = (
+            PreadQueryRecord::decoder(false),
+            PreadQueryRecord::decoder(true),
+        );
+        let mut records = vec![];
+        (narrow_decoder.decode)(row.as_bytes(), &mut records).unwrap();
+        (wide_decoder.decode)(wide.as_bytes(), &mut records).unwrap();
+        assert_eq!(records[0], PreadQueryRecord::from(row));
+        assert_eq!((records[0].fd, records[0].count, records[0].pid), (3, 2, 0));
+        assert_eq!(
+            (records[0].window_start, records[1].window_start),
+            (100, 100)
+        );
+        assert_eq!(
+            (records[1].avg_count, records[1].pid, records[1].sum_count),
+            (6, 42, 12)
+        );
+        assert_eq!(
+            (narrow_decoder.decode)(wide.as_bytes(), &mut records),
+            Err(DecodeError::Length {
+                record_size: narrow_decoder.record_size,
+                actual: wide_decoder.record_size,
+            })
+        );
+    }
+
+    #[test]
+    fn merges_percpu_aggregates() {
+        let agg = |count, min, max, sum| {
+            PercpuPreadAgg {
+                max,
+                ext: ExtAgg {
+                    count,
+                    min,
+                    sum,
+                    ..Default::default()
+                },
+            }
+        };
+        let key = PercpuPreadKey {
+            window: 3,
+            gb: PreadGroupBy {
+                fd: 7,
+                ..Default::default()
+            },
+        };
+        // The idle CPU's zeroed min must not win
+        let aggs = [
+            agg(2, 4, 8, 12),
+            PercpuPreadAgg::default(),
+            agg(1, 16, 16, 16),
+        ];
+        let r = key.record(&aggs, Aggs::MIN);
+        assert_eq!(
+            (r.fd, r.count, r.max_count, r.avg_count, r.min_count),
+            (7, 3, 16, 9, 4)
+        );
+        assert_eq!(r.sum_count, 0);
+        assert_eq!(key.record(&aggs, Aggs::SUM).sum_count, 28);
     }
 }
@@ -0,0 +1,194 @@
+use std::{
+    cell::RefCell,
+    fmt::Display,
+    rc::Rc,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use serde::Serialize;
+
+use crate::{bpf_structs::Decode, probe::Consumer};
+
+/// Batches in flight between the poller and the consumer in
+/// [`Consume::Threaded`] mode.
+pub const CHANNEL_BATCHES: usize = 1024;
+
+/// Where [`crate::probe::ProbeRunner::run`] runs the probe's consumer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Consume {
+    /// A poller thread decodes samples and sends the batches over a bounded
+    /// channel to the consumer on the main thread. When the consumer lags, the
+    /// poller blocks on the full channel and the kernel buffer fills up.
+    #[default]
+    Threaded,
+    /// The main thread polls the output buffer and consumes each sample from
+    /// the buffer callback, so consuming delays reading the next sample.
+    Inline,
+}
+
+impl Consume {
+    pub const NAMES: [&'static str; 2] = ["threaded", "inline"];
+}
+
+impl FromStr for Consume {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "threaded" => Ok(Self::Threaded),
+            "inline" => Ok(Self::Inline),
+            _ => Err(anyhow!("Unknown consume mode {s}")),
+        }
+    }
+}
+
+impl Display for Consume {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// Counters of how batches make their way from the output buffer to the
+/// consumer, updated by the sample callback and the run loop.
+#[derive(Debug, Default)]
+pub struct ConsumeStats {
+    /// Batches handed to the consumer, including windows closed in userspace.
+    pub batches: AtomicU64,
+    /// Batch buffers allocated because none was free to reuse.
+    pub allocations: AtomicU64,
+    /// Time spent in [`Consumer::consume`].
+    pub consume_ns: AtomicU64,
+    /// Most batches seen waiting in the channel.
+    pub max_depth: AtomicU64,
+    /// Sends that found the channel full.
+    pub blocked_sends: AtomicU64,
+    /// Time the poller spent waiting on a full channel.
+    pub blocked_ns: AtomicU64,
+}
+
+impl ConsumeStats {
+    /// Counters under their run report names; the channel ones are only
+    /// reported in [`Consume::Threaded`] mode.
+    pub fn counts(&self, mode: Consume) -> Vec<(&'static str, u64)> {
+        let mut counts = vec![
+            ("consume_batches", self.batches.load(Relaxed)),
+            ("consume_batch_allocations", self.allocations.load(Relaxed)),
+            ("consume_us", self.consume_ns.load(Relaxed) / 1000),
+        ];
+        if mode == Consume::Threaded {
+            counts.extend([
+                ("channel_max_depth", self.max_depth.load(Relaxed)),
+                ("channel_blocked_sends", self.blocked_sends.load(Relaxed)),
+                ("channel_blocked_us", self.blocked_ns.load(Relaxed) / 1000),
+            ]);
+        }
+        counts
+    }
+
+    /// Hands `records` to `consumer`, timing it.
+    pub fn consume<T>(&self, consumer: &mut dyn Consumer<T>, records: &[T]) {
+        self.batches.fetch_add(1, Relaxed);
+        let start = Instant::now();
+        consumer.consume(records);
+        self.consume_ns
+            .fetch_add(start.elapsed().as_nanos() as u64, Relaxed);
+    }
+}
+
+/// Decodes each sample with `decode` into a batch buffer taken from `free`, or
+/// a new one if none is free, and sends the batch to `tx`. The receiver passes
+/// buffers back through `free` once consumed, so in steady state nothing is
+/// allocated. Once the receiver is gone, samples are dropped.
+pub fn batch_sender<T>(
+    decode: Decode<T>,
+    tx: Sender<Vec<T>>,
+    free: Receiver<Vec<T>>,
+    stats: Arc<ConsumeStats>,
+) -> impl FnMut(&[u8]) -> i32 {
+    move |buf: &[u8]| -> i32 {
+        let mut batch = free.try_recv().unwrap_or_else(|_| {
+            stats.allocations.fetch_add(1, Relaxed);
+            Vec::new()
+        });
+        batch.clear();
+        if let Err(e) = decode(buf, &mut batch) {
+            eprintln!("Failed to decode records: {e}");
+            return 1;
+        }
+        stats.max_depth.fetch_max(tx.len() as u64 + 1, Relaxed);
+
+        let batch = match tx.try_send(batch) {
+            Ok(()) => return 0,
+            Err(TrySendError::Full(batch)) => batch,
+            Err(TrySendError::Disconnected(_)) => return 1,
+        };
+        stats.blocked_sends.fetch_add(1, Relaxed);
+        let start = Instant::now();
+        let sent = tx.send(batch);
+        stats
+            .blocked_ns
+            .fetch_add(start.elapsed().as_nanos() as u64, Relaxed);
+        if sent.is_err() {
+            return 1;
+        }
+        0
+    }
+}
+
+/// Decodes each sample with `decode` into a single reused buffer and hands it
+/// straight to `consumer`, on whichever thread polls the output buffer.
+pub fn inline_handler<T>(
+    decode: Decode<T>,
+    consumer: Rc<RefCell<Box<dyn Consumer<T>>>>,
+    stats: Arc<ConsumeStats>,
+) -> impl FnMut(&[u8]) -> i32 {
+    let mut batch = Vec::new();
+    move |buf: &[u8]| -> i32 {
+        let capacity = batch.capacity();
+        batch.clear();
+        if let Err(e) = decode(buf, &mut batch) {
+            eprintln!("Failed to decode records: {e}");
+            return 1;
+        }
+        if batch.capacity() != capacity {
+            stats.allocations.fetch_add(1, Relaxed);
+        }
+        stats.consume(&mut **consumer.borrow_mut(), &batch);
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam::channel;
+
+    use super::*;
+    use crate::bpf_structs::FromBytes;
+
+    #[test]
+    fn batch_sender_reuses_consumed_buffers() {
+        let (tx, rx) = channel::bounded(4);
+        let (free_tx, free_rx) = channel::bounded(4);
+        let stats = Arc::new(ConsumeStats::default());
+        let mut handler = batch_sender(u64::extend_from_bytes, tx, free_rx, stats.clone());
+
+        let sample: Vec<u8> = [1u64, 2].iter().flat_map(|v| v.to_ne_bytes()).collect();
+        for _ in 0..3 {
+            assert_eq!(handler(&sample), 0);
+            let batch = rx.try_recv().unwrap();
+            assert_eq!(batch, [1, 2]);
+            free_tx.send(batch).unwrap();
+        }
+        assert_eq!(stats.allocations.load(Relaxed), 1);
+        assert_eq!(stats.blocked_sends.load(Relaxed), 0);
+        assert_eq!(stats.max_depth.load(Relaxed), 1);
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    fs,
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::bpf_structs::PreadQueryRecord;
+
+/// Kind of a file read by the rocksdb application, from its name. SST levels
+/// are not part of the file name, so all SSTs share one kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Sst,
+    Wal,
+    Manifest,
+    Other,
+    /// The fd could not be resolved to a path.
+    Unresolved,
+}
+
+impl FileKind {
+    pub const ALL: [FileKind; 5] = [
+        Self::Sst,
+        Self::Wal,
+        Self::Manifest,
+        Self::Other,
+        Self::Unresolved,
+    ];
+
+    pub fn from_path(path: &Path) -> Self {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("sst") => Self::Sst,
+            Some("log") => Self::Wal,
+            _ if name.starts_with("MANIFEST-") => Self::Manifest,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl Display for FileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Sst => "sst",
+            Self::Wal => "wal",
+            Self::Manifest => "manifest",
+            Self::Other => "other",
+            Self::Unresolved => "unresolved",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Resolves a process's fds to paths through `/proc/<pid>/fd`. Resolved paths
+/// are cached until [`FdResolver::invalidate`], since the process may close an
+/// fd and reuse the number for another file; misses are always looked up
+/// again.
+#[derive(Debug)]
+pub struct FdResolver {
+    pid: u32,
+    cache: HashMap<u64, String>,
+}
+
+impl FdResolver {
+    pub fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn resolve(&mut self, fd: u64) -> Option<&str> {
+        if !self.cache.contains_key(&fd) {
+            let link = fs::read_link(format!("/proc/{}/fd/{fd}", self.pid)).ok()?;
+            self.cache.insert(fd, link.to_string_lossy().into_owned());
+        }
+        self.cache.get(&fd).map(String::as_str)
+    }
+
+    pub fn invalidate(&mut self) {
+        self.cache.clear();
+    }
+}
+
+/// pread totals of one file over a run.
+#[derive(Clone, Debug, Serialize)]
+pub struct FileStats {
+    /// Path of the file, or `fd:<n>` if its fd could not be resolved.
+    pub path: String,
+    pub kind: FileKind,
+    pub preads: u64,
+    /// Bytes requested by the preads, i.e. the sum of their `count` arguments.
+    pub requested_bytes: u64,
+    pub max_request: u64,
+}
+
+/// Totals pread query rows per file. Rows of the same fd on different CPUs
+/// are merged, and fds are resolved against the process `pid` if given.
+#[derive(Debug, Default)]
+pub struct FileGroups {
+    resolver: Option<FdResolver>,
+    files: BTreeMap<String, FileStats>,
+}
+
+impl FileGroups {
+    pub fn new(pid: Option<u32>) -> Self {
+        Self {
+            resolver: pid.map(FdResolver::new),
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the rows of one window. The fd cache is dropped afterwards, so fds
+    /// reused in the next window are looked up again.
+    pub fn add_window(&mut self, records: &[PreadQueryRecord]) {
+        for r in records {
+            let resolved = self.resolver.as_mut().and_then(|res| res.resolve(r.fd));
+            let (path, kind) = match resolved {
+                Some(path) => (path.to_string(), FileKind::from_path(Path::new(path))),
+                None => (format!("fd:{}", r.fd), FileKind::Unresolved),
+            };
+            let stats = self.files.entry(path).or_insert_with_key(|path| {
+                FileStats {
+                    path: path.clone(),
+                    kind,
+                    preads: 0,
+                    requested_bytes: 0,
+                    max_request: 0,
+                }
+            });
+            stats.preads += r.count;
+            stats.requested_bytes = stats
+                .requested_bytes
+                .saturating_add(r.count.saturating_mul(r.avg_count));
+            stats.max_request = stats.max_request.max(r.max_count);
+        }
+        if let Some(resolver) = &mut self.resolver {
+            resolver.invalidate();
+        }
+    }
+
+    /// Files by descending number of preads.
+    pub fn files(&self) -> Vec<FileStats> {
+        let mut files: Vec<_> = self.files.values().cloned().collect();
+        files.sort_by(|a, b| b.preads.cmp(&a.preads).then_with(|| a.path.cmp(&b.path)));
+        files
+    }
+}
+
+/// Sums file stats per kind, in [`FileKind::ALL`] order; kinds without files
+/// are included as zeros.
+pub fn totals_by_kind(files: &[FileStats]) -> Vec<(FileKind, u64, u64)> {
+    FileKind::ALL
+        .iter()
+        .map(|&kind| {
+            let of_kind = files.iter().filter(|f| f.kind == kind);
+            let (preads, bytes) =
+                of_kind.fold((0, 0), |(p, b), f| (p + f.preads, b + f.requested_bytes));
+            (kind, preads, bytes)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn classifies_rocksdb_files() {
+        let kind = |p| FileKind::from_path(Path::new(p));
+        assert_eq!(kind("/db/000123.sst"), FileKind::Sst);
+        assert_eq!(kind("/db/000124.log"), FileKind::Wal);
+        assert_eq!(kind("/db/MANIFEST-000005"), FileKind::Manifest);
+        assert_eq!(kind("/db/LOG"), FileKind::Other);
+        assert_eq!(kind("/db/OPTIONS-000007"), FileKind::Other);
+    }
+
+    #[test]
+    fn groups_unresolved_fds_across_cpus() {
+        let mut groups = FileGroups::new(None);
+        let row = |cpu, count, max_count, avg_count| {
+            PreadQueryRecord {
+                fd: 7,
+                cpu,
+                count,
+                max_count,
+                avg_count,
+                ..Default::default()
+            }
+        };
+        groups.add_window(&[row(0, 2, 8, 6), row(1, 1, 4, 4)]);
+        let files = groups.files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "fd:7");
+        assert_eq!(files[0].kind, FileKind::Unresolved);
+        assert_eq!(
+            (
+                files[0].preads,
+                files[0].requested_bytes,
+                files[0].max_request
+            ),
+            (3, 16, 8)
+        );
+    }
+}
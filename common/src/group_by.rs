@@ -0,0 +1,153 @@
+use std::{fmt::Display, mem, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Serialize, Serializer};
+
+use crate::bpf_structs::{PreadGroupBy, PreadQueryRecord};
+
+/// Group by fields of the pread query, as the bit mask the BPF programs read
+/// from their `GROUP_BY` rodata. The bits match `enum GROUP_BY_FIELD` in
+/// `pread_query.bpf.h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GroupBy(u32);
+
+impl GroupBy {
+    pub const CGROUP: Self = Self(1 << 4);
+    pub const COMM: Self = Self(1 << 5);
+    pub const CPU: Self = Self(1 << 1);
+    pub const FD: Self = Self(1 << 0);
+    pub const FIELDS: [(&'static str, Self); 6] = [
+        ("fd", Self::FD),
+        ("cpu", Self::CPU),
+        ("tid", Self::TID),
+        ("pid", Self::PID),
+        ("cgroup", Self::CGROUP),
+        ("comm", Self::COMM),
+    ];
+    /// Kernel tgid, i.e. the process.
+    pub const PID: Self = Self(1 << 3);
+    /// Kernel pid, i.e. the thread.
+    pub const TID: Self = Self(1 << 2);
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Key size of the ebql and opt aggregation maps: [`PreadGroupBy`] up to
+    /// its last selected field, and at least fd and cpu.
+    pub fn key_bytes(self) -> u32 {
+        let ends = [
+            (Self::COMM, mem::size_of::<PreadGroupBy>()),
+            (Self::CGROUP, mem::offset_of!(PreadGroupBy, comm)),
+            (Self::PID, mem::offset_of!(PreadGroupBy, cgroup)),
+            (Self::TID, mem::offset_of!(PreadGroupBy, pid)),
+        ];
+        let end = ends
+            .into_iter()
+            .find(|&(field, _)| self.contains(field))
+            .map_or(mem::offset_of!(PreadGroupBy, tid), |(_, end)| end);
+        end as u32
+    }
+
+    /// The selected fields of `r`'s group, e.g. `fd=3 comm=rocksdb:low`.
+    pub fn key(self, r: &PreadQueryRecord) -> String {
+        let mut parts = vec![];
+        for (name, field) in Self::FIELDS {
+            if !self.contains(field) {
+                continue;
+            }
+            let value = match field {
+                Self::FD => r.fd.to_string(),
+                Self::CPU => r.cpu.to_string(),
+                Self::TID => r.tid.to_string(),
+                Self::PID => r.pid.to_string(),
+                Self::CGROUP => r.cgroup.to_string(),
+                _ => r.comm().to_string(),
+            };
+            parts.push(format!("{name}={value}"));
+        }
+        parts.join(" ")
+    }
+}
+
+impl Default for GroupBy {
+    fn default() -> Self {
+        Self(Self::FD.0 | Self::CPU.0)
+    }
+}
+
+/// Parses a comma-separated list of fields, e.g. `fd,comm`.
+impl FromStr for GroupBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut bits = 0;
+        for name in s.split(',').map(str::trim) {
+            let (_, field) = Self::FIELDS
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow!("Unknown group by field {name}"))?;
+            bits |= field.0;
+        }
+        if bits == 0 {
+            bail!("Group by needs at least one field");
+        }
+        Ok(Self(bits))
+    }
+}
+
+impl Display for GroupBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<_> = Self::FIELDS
+            .iter()
+            .filter(|(_, field)| self.contains(*field))
+            .map(|(name, _)| *name)
+            .collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+impl Serialize for GroupBy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats_fields() {
+        let by: GroupBy = "comm, FD".parse().unwrap();
+        assert_eq!(by.bits(), 0b100001);
+        assert_eq!(by.to_string(), "fd,comm");
+        assert!("fd,inode".parse::<GroupBy>().is_err());
+    }
+
+    #[test]
+    fn key_shows_selected_fields() {
+        let mut r = PreadQueryRecord {
+            fd: 3,
+            cpu: 1,
+            ..Default::default()
+        };
+        r.comm[..7].copy_from_slice(b"rocksdb");
+        let by: GroupBy = "fd,comm".parse().unwrap();
+        assert_eq!(by.key(&r), "fd=3 comm=rocksdb");
+    }
+
+    #[test]
+    fn key_bytes_end_at_last_selected_field() {
+        let key_bytes = |s: &str| s.parse::<GroupBy>().unwrap().key_bytes();
+        assert_eq!(GroupBy::default().key_bytes(), 16);
+        assert_eq!(key_bytes("cpu"), 16);
+        assert_eq!(key_bytes("fd,pid"), 32);
+        assert_eq!(key_bytes("tid"), 24);
+        assert_eq!(key_bytes("fd,comm"), 56);
+    }
+}
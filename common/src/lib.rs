@@ -1,4 +1,17 @@
+pub mod aggs;
+pub mod bpf_errors;
+pub mod bpf_log;
 pub mod bpf_prog;
 pub mod bpf_stats;
 pub mod bpf_structs;
+pub mod consume;
+pub mod files;
+pub mod group_by;
+pub mod poller;
+pub mod probe;
 pub mod prog_stats;
+pub mod report;
+pub mod syscall;
+pub mod transport;
+pub mod validate;
+pub mod window;
@@ -0,0 +1,104 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::transport::Output;
+
+/// Polls probe output buffers on a thread of its own until shut down, then
+/// consumes whatever they still hold. The thread sleeps in `epoll_wait` on the
+/// buffers' epoll fds and an eventfd that [`Poller::shutdown`] signals, so it
+/// neither wakes up on a timeout nor outlives the run.
+pub struct Poller {
+    shutdown: OwnedFd,
+    handle: JoinHandle<Result<()>>,
+}
+
+impl Poller {
+    pub fn spawn(outputs: Vec<Output>) -> Result<Self> {
+        let epoll = owned_fd(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })
+            .context("Failed to create an epoll instance")?;
+        let shutdown = owned_fd(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) })
+            .context("Failed to create the shutdown eventfd")?;
+        // Outputs are woken up by their index, the eventfd by the one after
+        for (i, output) in outputs.iter().enumerate() {
+            epoll_add(&epoll, output.epoll_fd(), i as u64)?;
+        }
+        epoll_add(&epoll, shutdown.as_raw_fd(), outputs.len() as u64)?;
+        let handle = thread::spawn(move || poll_until_shutdown(epoll, outputs));
+        Ok(Self { shutdown, handle })
+    }
+
+    /// Tells the thread to consume what is left in the buffers and exit.
+    pub fn shutdown(&self) -> Result<()> {
+        let one = 1u64.to_ne_bytes();
+        let ret = unsafe { libc::write(self.shutdown.as_raw_fd(), one.as_ptr().cast(), one.len()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to signal the poller");
+        }
+        Ok(())
+    }
+
+    /// Waits for the thread to exit after [`Poller::shutdown`]. The sample
+    /// callbacks block on a full channel, so keep receiving until the
+    /// channels close, which they do once the thread drops the buffers.
+    pub fn join(self) -> Result<()> {
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("Output poller panicked"))?
+    }
+}
+
+fn poll_until_shutdown(epoll: OwnedFd, outputs: Vec<Output>) -> Result<()> {
+    let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; outputs.len() + 1];
+    loop {
+        let n = unsafe {
+            libc::epoll_wait(
+                epoll.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.len() as i32,
+                -1,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            // Ctrl-C lands on any thread
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e).context("Failed to wait for output buffers");
+        }
+        for event in &events[..n as usize] {
+            let i = event.u64 as usize;
+            if i == outputs.len() {
+                for output in &outputs {
+                    output.consume()?;
+                }
+                return Ok(());
+            }
+            outputs[i].consume()?;
+        }
+    }
+}
+
+fn epoll_add(epoll: &OwnedFd, fd: RawFd, token: u64) -> Result<()> {
+    let mut event = libc::epoll_event {
+        events: libc::EPOLLIN as u32,
+        u64: token,
+    };
+    let ret = unsafe { libc::epoll_ctl(epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut event) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to add an fd to epoll");
+    }
+    Ok(())
+}
+
+fn owned_fd(fd: RawFd) -> io::Result<OwnedFd> {
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
@@ -0,0 +1,444 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use crossbeam::channel;
+use libbpf_rs::{ErrorKind, Map, Program};
+
+use crate::{
+    aggs::Aggs,
+    bpf_errors::BpfErrors,
+    bpf_log::BpfLogLevel,
+    bpf_stats::{self, BpfProgram, BpfStatsSampler},
+    bpf_structs::{Decoder, FromBytes},
+    consume::{self, Consume, ConsumeStats},
+    files::FileStats,
+    group_by::GroupBy,
+    poller::Poller,
+    syscall::Syscall,
+    transport::{Output, Transport},
+    validate::Validator,
+    window::WindowSpec,
+};
+
+/// Callback invoked by the output buffer for every submitted sample.
+pub type SampleCallback = Box<dyn FnMut(&[u8]) -> i32>;
+
+/// Userspace half of a probe: receives decoded records from the output buffer.
+pub trait Consumer<T> {
+    /// Takes in a batch of records. The batch is borrowed, as its buffer is
+    /// reused for later samples.
+    fn consume(&mut self, records: &[T]);
+
+    /// Number of result rows produced so far.
+    fn records(&self) -> usize;
+
+    /// Called once after probing stops.
+    fn finish(&mut self) {}
+
+    /// Per-file totals of the rows, for consumers that group by file.
+    fn files(&self) -> Vec<FileStats> {
+        Vec::new()
+    }
+
+    /// Extra counts for the run report, e.g. totals of optional aggregates.
+    fn counts(&self) -> BTreeMap<&'static str, u64> {
+        BTreeMap::new()
+    }
+}
+
+/// Run options a probe supports beyond the ring buffer transport (unless
+/// `mappoll_only`), tumbling windows and grouping by fd and cpu. Checked
+/// before the probe runs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Capabilities {
+    /// Groups by other fields with [`RunOptions::group_by`].
+    pub group_by: bool,
+    /// Computes the optional aggregates of [`RunOptions::aggs`].
+    pub aggs: bool,
+    /// Sliding, count and session windows.
+    pub windows: bool,
+    /// Submits through the perf buffer with [`Transport::Perfbuf`].
+    pub perfbuf: bool,
+    /// Leaves its windows in maps for [`Transport::Mappoll`].
+    pub mappoll: bool,
+    /// Has no ring buffer, so [`Transport::Mappoll`] is its only transport.
+    pub mappoll_only: bool,
+    /// Aggregates in the kernel and is checked against the userspace
+    /// reference query by this validator.
+    pub validate: Option<Validator>,
+}
+
+/// Picks one of the [`Capabilities`].
+pub type Supports = fn(&Capabilities) -> bool;
+
+/// A probe variant: a BPF skeleton, the record type it submits and the consumer
+/// for those records. Load-time configuration of the skeleton goes between
+/// `open` and `load`.
+pub trait Probe {
+    type Record: FromBytes + Send + 'static;
+
+    fn capabilities() -> Capabilities {
+        Capabilities::default()
+    }
+
+    fn open(&mut self) -> Result<()>;
+
+    /// Applies the load-time parameters in `opts` to the opened skeleton.
+    fn configure(&mut self, opts: &RunOptions) -> Result<()>;
+
+    fn load(&mut self) -> Result<()>;
+
+    fn attach(&mut self) -> Result<()>;
+
+    /// Builds the probe's output buffer for the configured transport, handing
+    /// samples to `callback`, or `None` for probes whose results are only read
+    /// in [`Probe::flush`].
+    fn output(&self, callback: SampleCallback) -> Result<Option<Output>>;
+
+    fn consumer(&self) -> Box<dyn Consumer<Self::Record>>;
+
+    /// How the configured probe's samples decode into records.
+    fn decoder(&self) -> Decoder<Self::Record> {
+        Decoder::default()
+    }
+
+    /// Programs of the loaded skeleton; empty before `load`.
+    fn programs(&self) -> Vec<&Program>;
+
+    /// The loaded skeleton's error counter map; `None` before `load`.
+    fn error_counts(&self) -> Option<&Map>;
+
+    /// Closes the probe's windows that are due, or every open window if
+    /// `force`, for probes whose windows would otherwise stay open until the
+    /// next event. Returns the windows closed in userspace; those closed in
+    /// the kernel arrive through the output buffer as usual.
+    fn flush(&mut self, _force: bool) -> Result<Vec<Vec<Self::Record>>> {
+        Ok(Vec::new())
+    }
+}
+
+/// How often [`ProbeRunner::run`] flushes the probe's due windows.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Settings for [`ProbeRunner::run`] that apply to every probe.
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    /// Samples the probe's BPF program stats into this CSV file while running.
+    pub samples_path: Option<PathBuf>,
+    pub sample_interval: Duration,
+    /// Window of windowed queries; `None` keeps the probe's default.
+    pub window: Option<WindowSpec>,
+    /// Maximum number of groups a query tracks per window.
+    pub max_groups: Option<u32>,
+    /// Size of the probe's output ring buffer; libbpf rounds it up to a
+    /// power-of-two number of pages.
+    pub ringbuf_bytes: Option<u32>,
+    /// How the probe's programs hand records to userspace.
+    pub transport: Transport,
+    /// Where the probe's consumer runs.
+    pub consume: Consume,
+    /// Threshold of the BPF programs' log statements.
+    pub bpf_log_level: BpfLogLevel,
+    pub filter: TaskFilter,
+    /// Group by fields of the pread queries that aggregate in the kernel.
+    pub group_by: GroupBy,
+    /// Optional aggregates of the pread queries.
+    pub aggs: Aggs,
+    /// Syscalls aggregated by the syscall probe; the other probes ignore it.
+    pub syscalls: Vec<Syscall>,
+}
+
+impl RunOptions {
+    /// Window length for probes that only support tumbling windows.
+    pub fn tumbling_window(&self) -> Option<Duration> {
+        match self.window {
+            Some(WindowSpec::Tumbling(size)) => Some(size),
+            _ => None,
+        }
+    }
+}
+
+/// Restricts a probe to the events of some tasks. Unset fields match every
+/// task; set fields must all match.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TaskFilter {
+    /// Kernel pid, i.e. the id of a single thread.
+    pub pid: Option<u32>,
+    /// Kernel tgid, i.e. the process id userspace sees.
+    pub tgid: Option<u32>,
+    /// cgroup v2 id.
+    pub cgroup: Option<u64>,
+}
+
+impl TaskFilter {
+    /// Resolves a cgroup given either as its id or as a path to its cgroup v2
+    /// directory, absolute or relative to `/sys/fs/cgroup`.
+    pub fn cgroup_id(cgroup: &str) -> Result<u64> {
+        if let Ok(id) = cgroup.parse() {
+            return Ok(id);
+        }
+        let path = Path::new("/sys/fs/cgroup").join(cgroup);
+        // On cgroup v2, a cgroup's id is the inode number of its directory
+        let meta = fs::metadata(&path)
+            .with_context(|| format!("Failed to find cgroup {}", path.display()))?;
+        Ok(meta.ino())
+    }
+
+    /// Process whose fds the probe's records refer to, if the filter pins one
+    /// down. A thread id works as well, since `/proc/<tid>` shares its
+    /// process's fd table.
+    pub fn process(&self) -> Option<u32> {
+        self.tgid.or(self.pid)
+    }
+}
+
+/// What [`ProbeRunner::run`] produced.
+#[derive(Debug, Default)]
+pub struct RunOutput {
+    /// Number of result rows.
+    pub records: usize,
+    pub files: Vec<FileStats>,
+    pub counts: BTreeMap<&'static str, u64>,
+}
+
+/// Object-safe view of a [`Probe`], so probes with different record types can
+/// share a registry.
+pub trait ProbeRunner {
+    /// Opens, loads and attaches the probe, then consumes records until `done`
+    /// is set.
+    fn run(&mut self, done: Arc<AtomicBool>, opts: &RunOptions) -> Result<RunOutput>;
+
+    /// Runtime stats of the probe's own programs.
+    fn prog_stats(&self) -> Result<Vec<BpfProgram>>;
+
+    /// Errors counted by the probe's programs; all zero before `load`.
+    fn bpf_errors(&self) -> Result<BpfErrors>;
+}
+
+impl<P: Probe> ProbeRunner for P {
+    fn run(&mut self, done: Arc<AtomicBool>, opts: &RunOptions) -> Result<RunOutput> {
+        self.open()?;
+        self.configure(opts)?;
+        self.load()?;
+
+        // In threaded mode, batches travel to the consumer over `rx` and their
+        // buffers back over `free_rx`. The runner holds on to a sender until
+        // the end, so the channel stays open for probes without an output
+        // buffer.
+        let consumer = Rc::new(RefCell::new(self.consumer()));
+        let stats = Arc::new(ConsumeStats::default());
+        let (tx, rx) = channel::bounded(consume::CHANNEL_BATCHES);
+        let (free_tx, free_rx) = channel::bounded(consume::CHANNEL_BATCHES);
+        let decode = self.decoder().decode;
+        let callback: SampleCallback = match opts.consume {
+            Consume::Threaded => {
+                Box::new(consume::batch_sender(
+                    decode,
+                    tx.clone(),
+                    free_rx,
+                    stats.clone(),
+                ))
+            }
+            Consume::Inline => {
+                Box::new(consume::inline_handler(
+                    decode,
+                    consumer.clone(),
+                    stats.clone(),
+                ))
+            }
+        };
+        let output = self.output(callback)?;
+        let lost_samples = output.as_ref().and_then(Output::lost_samples);
+        self.attach()?;
+
+        let sampler = match &opts.samples_path {
+            Some(path) => {
+                Some(BpfStatsSampler::spawn(
+                    self.programs(),
+                    self.error_counts(),
+                    opts.sample_interval,
+                    path,
+                )?)
+            }
+            None => None,
+        };
+
+        match opts.consume {
+            Consume::Threaded => {
+                let poller = output
+                    .map(|output| Poller::spawn(vec![output]))
+                    .transpose()?;
+                let consumer = &mut **consumer.borrow_mut();
+                let flush_ticks = channel::tick(FLUSH_INTERVAL);
+                while !done.load(SeqCst) {
+                    channel::select! {
+                        recv(rx) -> batch => {
+                            if let Ok(batch) = batch {
+                                stats.consume(consumer, &batch);
+                                let _ = free_tx.try_send(batch);
+                            }
+                        }
+                        recv(flush_ticks) -> _ => {
+                            for window in self.flush(false)? {
+                                stats.consume(consumer, &window);
+                            }
+                        }
+                    }
+                }
+                // Emit the windows still open, then read everything up to
+                // them; the channel closes once the poller has consumed the
+                // output buffer and dropped it.
+                for window in self.flush(true)? {
+                    stats.consume(consumer, &window);
+                }
+                if let Some(poller) = &poller {
+                    poller.shutdown()?;
+                }
+                drop(tx);
+                for batch in rx.iter() {
+                    stats.consume(consumer, &batch);
+                }
+                if let Some(poller) = poller {
+                    poller.join()?;
+                }
+            }
+            Consume::Inline => {
+                // The output buffer's callback borrows the consumer while
+                // polling, so only borrow it in between.
+                let mut last_flush = Instant::now();
+                while !done.load(SeqCst) {
+                    match &output {
+                        // Ctrl-C may interrupt the wait
+                        Some(output) => {
+                            match output.poll(FLUSH_INTERVAL) {
+                                Err(e) if e.kind() != ErrorKind::Interrupted => {
+                                    return Err(e.into())
+                                }
+                                _ => {}
+                            }
+                        }
+                        None => thread::sleep(FLUSH_INTERVAL),
+                    }
+                    if last_flush.elapsed() >= FLUSH_INTERVAL {
+                        for window in self.flush(false)? {
+                            stats.consume(&mut **consumer.borrow_mut(), &window);
+                        }
+                        last_flush = Instant::now();
+                    }
+                }
+                for window in self.flush(true)? {
+                    stats.consume(&mut **consumer.borrow_mut(), &window);
+                }
+                if let Some(output) = &output {
+                    output.consume()?;
+                }
+            }
+        }
+        let mut consumer = consumer.borrow_mut();
+        consumer.finish();
+        if let Some(sampler) = sampler {
+            sampler.stop()?;
+        }
+
+        let mut counts = consumer.counts();
+        counts.extend(stats.counts(opts.consume));
+        if let Some(lost) = lost_samples {
+            counts.insert("perfbuf_lost_samples", lost.load(SeqCst));
+        }
+        Ok(RunOutput {
+            records: consumer.records(),
+            files: consumer.files(),
+            counts,
+        })
+    }
+
+    fn prog_stats(&self) -> Result<Vec<BpfProgram>> {
+        bpf_stats::get_probe_stats(self.programs())
+    }
+
+    fn bpf_errors(&self) -> Result<BpfErrors> {
+        self.error_counts()
+            .map_or_else(|| Ok(BpfErrors::default()), |map| BpfErrors::read(map))
+    }
+}
+
+pub struct ProbeEntry {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub capabilities: Capabilities,
+    create: fn() -> Box<dyn ProbeRunner>,
+}
+
+/// Named probe variants selectable from the command line.
+#[derive(Default)]
+pub struct ProbeRegistry {
+    entries: Vec<ProbeEntry>,
+}
+
+impl ProbeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<P>(&mut self, name: &'static str, description: &'static str) -> &mut Self
+    where
+        P: Probe + Default + 'static,
+    {
+        assert!(
+            self.get(name).is_none(),
+            "Probe {name} registered more than once"
+        );
+        self.entries.push(ProbeEntry {
+            name,
+            description,
+            capabilities: P::capabilities(),
+            create: create_probe::<P>,
+        });
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ProbeEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    pub fn create(&self, name: &str) -> Option<Box<dyn ProbeRunner>> {
+        self.get(name).map(|e| (e.create)())
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.entries.iter().map(|e| e.name).collect()
+    }
+
+    pub fn entries(&self) -> &[ProbeEntry] {
+        &self.entries
+    }
+
+    /// Names of the probes whose capabilities satisfy `supports`.
+    pub fn supporting(&self, supports: Supports) -> Vec<&'static str> {
+        self.entries
+            .iter()
+            .filter(|e| supports(&e.capabilities))
+            .map(|e| e.name)
+            .collect()
+    }
+}
+
+fn create_probe<P>() -> Box<dyn ProbeRunner>
+where
+    P: Probe + Default + 'static,
+{
+    Box::new(P::default())
+}
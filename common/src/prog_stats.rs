@@ -2,14 +2,21 @@ use std::fmt::Display;
 
 use chrono::{Duration, Local};
 use procfs::{process::Process, WithCurrentSystemInfo};
+use serde::{Serialize, Serializer};
 
+#[derive(Debug, Serialize)]
 pub struct ProgStats {
     pub utime: u64,
     pub stime: u64,
+    #[serde(rename = "runtime_ns", serialize_with = "serialize_ns")]
     pub runtime: Duration,
     pub clock_tps: u64,
 }
 
+fn serialize_ns<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_i64(d.num_nanoseconds().unwrap())
+}
+
 impl Display for ProgStats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -17,7 +24,7 @@ impl Display for ProgStats {
             "{}, {}, {}, {}",
             self.utime,
             self.stime,
-            self.runtime.num_nanoseconds().unwrap(),
+            self.runtime_ns(),
             self.clock_tps,
         )
     }
@@ -38,4 +45,8 @@ impl ProgStats {
             clock_tps: tps,
         }
     }
+
+    pub fn runtime_ns(&self) -> i64 {
+        self.runtime.num_nanoseconds().unwrap()
+    }
 }
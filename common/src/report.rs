@@ -0,0 +1,296 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use serde::Serialize;
+
+use crate::{
+    bpf_stats::BpfProgram,
+    files::{self, FileStats},
+    prog_stats::ProgStats,
+};
+
+/// Bumped whenever a field of [`RunReport`] is added, removed or changes
+/// meaning.
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+
+/// Summary of a single benchmark run, written as one JSON line and one CSV row.
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub schema_version: u32,
+    pub run_id: String,
+    pub binary: &'static str,
+    pub timestamp: String,
+    pub args: serde_json::Value,
+    pub host: HostInfo,
+    pub prog_stats: ProgStats,
+    pub bpf_programs: Vec<BpfProgram>,
+    pub counts: BTreeMap<&'static str, u64>,
+    pub latency_quantiles: Vec<Quantile>,
+    /// pread totals per file, by descending number of preads.
+    pub files: Vec<FileStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostInfo {
+    pub hostname: String,
+    pub kernel: String,
+    pub n_cpus: usize,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Quantile {
+    pub quantile: f64,
+    pub latency_secs: f64,
+}
+
+impl HostInfo {
+    pub fn get() -> Self {
+        let read = |path| {
+            fs::read_to_string(path)
+                .map(|s| s.trim().to_string())
+                .unwrap_or_default()
+        };
+        Self {
+            hostname: read("/proc/sys/kernel/hostname"),
+            kernel: read("/proc/sys/kernel/osrelease"),
+            n_cpus: std::thread::available_parallelism().map_or(0, |n| n.get()),
+        }
+    }
+}
+
+/// Run id used when none is given on the command line: start time plus pid.
+pub fn default_run_id() -> String {
+    format!("{}-{}", Local::now().format("%Y%m%dT%H%M%S"), process::id())
+}
+
+impl RunReport {
+    /// Creates a report for the current process, snapshotting its CPU usage
+    /// now.
+    pub fn new<A: Serialize>(binary: &'static str, run_id: String, args: &A) -> Result<Self> {
+        Ok(Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            run_id,
+            binary,
+            timestamp: Local::now().to_rfc3339(),
+            args: serde_json::to_value(args).context("Failed to serialize arguments")?,
+            host: HostInfo::get(),
+            prog_stats: ProgStats::get(),
+            bpf_programs: Vec::new(),
+            counts: BTreeMap::new(),
+            latency_quantiles: Vec::new(),
+            files: Vec::new(),
+        })
+    }
+
+    /// Appends the report to `path` as JSON lines and to `path.csv` as CSV.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        self.write_jsonl(path)?;
+        let mut csv_path = PathBuf::from(path).into_os_string();
+        csv_path.push(".csv");
+        self.write_csv(csv_path)
+    }
+
+    pub fn write_jsonl(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut f = open_append(path.as_ref())?;
+        serde_json::to_writer(&mut f, self)?;
+        writeln!(f)?;
+        Ok(())
+    }
+
+    /// Appends a CSV row, writing the header first if the file is empty. BPF
+    /// program stats are given per program (`bpf_<name>_*`) and summed over
+    /// all programs; file stats are only given summed per file kind.
+    ///
+    /// The columns vary with the probe and its options, so a row whose header
+    /// differs from the file's goes to a numbered file next to it instead; see
+    /// [`csv_file`].
+    pub fn write_csv(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let columns = self.csv_columns();
+        let header = columns
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        let csv_path = csv_file(path, &header)?;
+        if csv_path != path {
+            log::warn!(
+                "{} has other columns; appending to {}",
+                path.display(),
+                csv_path.display()
+            );
+        }
+        let mut f = open_append(&csv_path)?;
+        if f.metadata()?.len() == 0 {
+            writeln!(f, "{header}")?;
+        }
+        let row = columns.iter().map(|(_, v)| csv_escape(v));
+        writeln!(f, "{}", row.collect::<Vec<_>>().join(","))?;
+        Ok(())
+    }
+
+    fn csv_columns(&self) -> Vec<(String, String)> {
+        let stats = &self.prog_stats;
+        let mut columns = vec![
+            ("schema_version", self.schema_version.to_string()),
+            ("run_id", self.run_id.clone()),
+            ("binary", self.binary.to_string()),
+            ("timestamp", self.timestamp.clone()),
+            ("hostname", self.host.hostname.clone()),
+            ("kernel", self.host.kernel.clone()),
+            ("n_cpus", self.host.n_cpus.to_string()),
+            ("utime", stats.utime.to_string()),
+            ("stime", stats.stime.to_string()),
+            ("runtime_ns", stats.runtime_ns().to_string()),
+            ("clock_tps", stats.clock_tps.to_string()),
+            ("bpf_programs", self.bpf_programs.len().to_string()),
+            (
+                "bpf_run_time_ns",
+                self.bpf_programs
+                    .iter()
+                    .map(|p| p.run_time_ns)
+                    .sum::<u64>()
+                    .to_string(),
+            ),
+            (
+                "bpf_run_cnt",
+                self.bpf_programs
+                    .iter()
+                    .map(|p| p.run_cnt)
+                    .sum::<u64>()
+                    .to_string(),
+            ),
+            (
+                "bpf_recursion_misses",
+                self.bpf_programs
+                    .iter()
+                    .map(|p| p.recursion_misses)
+                    .sum::<u64>()
+                    .to_string(),
+            ),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect::<Vec<_>>();
+        for prog in &self.bpf_programs {
+            columns.extend([
+                (format!("bpf_{}_id", prog.name), prog.id.to_string()),
+                (
+                    format!("bpf_{}_run_time_ns", prog.name),
+                    prog.run_time_ns.to_string(),
+                ),
+                (
+                    format!("bpf_{}_run_cnt", prog.name),
+                    prog.run_cnt.to_string(),
+                ),
+            ]);
+        }
+        columns.extend(
+            self.counts
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+        columns.extend(
+            self.latency_quantiles
+                .iter()
+                .map(|q| (format!("p{}", q.quantile), q.latency_secs.to_string())),
+        );
+        for (kind, preads, bytes) in files::totals_by_kind(&self.files) {
+            columns.extend([
+                (format!("{kind}_preads"), preads.to_string()),
+                (format!("{kind}_requested_bytes"), bytes.to_string()),
+            ]);
+        }
+        columns.push(("args".to_string(), self.args.to_string()));
+        columns
+    }
+}
+
+/// Numbered CSV files tried after the first when the columns change.
+const MAX_CSV_FILES: u32 = 100;
+
+/// The CSV file to append rows with `header` to: the first of `path`,
+/// `<stem>.1.<ext>`, ..., `<stem>.<MAX_CSV_FILES>.<ext>` that is missing,
+/// empty or starts with `header`.
+fn csv_file(path: &Path, header: &str) -> Result<PathBuf> {
+    for n in 0..=MAX_CSV_FILES {
+        let candidate = if n == 0 {
+            path.to_path_buf()
+        } else {
+            let mut name = path.file_stem().unwrap_or_default().to_os_string();
+            name.push(format!(".{n}"));
+            if let Some(ext) = path.extension() {
+                name.push(".");
+                name.push(ext);
+            }
+            path.with_file_name(name)
+        };
+        let f = match fs::File::open(&candidate) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(candidate),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to open {}", candidate.display()))
+            }
+        };
+        let mut first = String::new();
+        BufReader::new(f)
+            .read_line(&mut first)
+            .with_context(|| format!("Failed to read {}", candidate.display()))?;
+        if first.is_empty() || first.trim_end_matches('\n') == header {
+            return Ok(candidate);
+        }
+    }
+    bail!(
+        "{} and its {MAX_CSV_FILES} numbered files all have other columns",
+        path.display()
+    )
+}
+
+fn open_append(path: &Path) -> Result<fs::File> {
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_rows_with_other_columns_go_to_a_new_file() {
+        let dir = std::env::temp_dir().join(format!("report-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("runs.csv");
+        fs::write(&path, "a,b\n1,2\n").unwrap();
+
+        assert_eq!(csv_file(&path, "a,b").unwrap(), path);
+        let numbered = dir.join("runs.1.csv");
+        assert_eq!(csv_file(&path, "a,b,c").unwrap(), numbered);
+        fs::write(&numbered, "a,b,c\n1,2,3\n").unwrap();
+        assert_eq!(csv_file(&path, "a,b,c").unwrap(), numbered);
+        assert_eq!(csv_file(&path, "a").unwrap(), dir.join("runs.2.csv"));
+        for n in 2..=MAX_CSV_FILES {
+            fs::write(dir.join(format!("runs.{n}.csv")), "z\n").unwrap();
+        }
+        assert!(csv_file(&path, "a").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+/// Syscalls the syscall probe can aggregate. The values match `enum SYSCALL`
+/// in `syscall_query.bpf.c` and are the `syscall` field of its records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u64)]
+pub enum Syscall {
+    Read = 0,
+    Pread64,
+    Write,
+    Pwrite64,
+    Fsync,
+    Preadv,
+    /// io_uring submissions. The submit tracepoint has no fd, so these are
+    /// grouped by sqe opcode instead and carry no size.
+    IoUring,
+}
+
+impl Syscall {
+    pub const ALL: [Syscall; 7] = [
+        Self::Read,
+        Self::Pread64,
+        Self::Write,
+        Self::Pwrite64,
+        Self::Fsync,
+        Self::Preadv,
+        Self::IoUring,
+    ];
+    pub const NAMES: [&'static str; 7] = [
+        "read", "pread64", "write", "pwrite64", "fsync", "preadv", "io_uring",
+    ];
+
+    pub fn from_id(id: u64) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+}
+
+impl FromStr for Syscall {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::NAMES
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(s))
+            .map(|i| Self::ALL[i])
+            .ok_or_else(|| anyhow!("Unknown syscall {s}"))
+    }
+}
+
+impl Display for Syscall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    fmt::Display,
+    os::fd::RawFd,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use libbpf_rs::{Map, PerfBuffer, PerfBufferBuilder, RingBuffer};
+use serde::Serialize;
+
+use crate::probe::SampleCallback;
+
+/// Name of the perf event array every probe gets from `common.bpf.h`.
+pub const PERF_BUF_MAP: &str = "perf_buf";
+
+/// How a probe's programs hand records to userspace; the values match
+/// `enum TRANSPORT` in `common.bpf.h` and are written to the `TRANSPORT`
+/// rodata.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Transport {
+    /// `bpf_ringbuf_reserve` into the probe's ring buffer, shared by all CPUs.
+    #[default]
+    Ringbuf = 0,
+    /// `bpf_perf_event_output` into a buffer per CPU; available before the
+    /// ring buffer (5.8), and drops are reported per CPU.
+    Perfbuf,
+    /// Nothing is submitted: userspace drains the aggregation maps once per
+    /// window with `BPF_MAP_LOOKUP_AND_DELETE_BATCH`, so windows are as large
+    /// as the maps rather than the ring buffer.
+    Mappoll,
+}
+
+impl Transport {
+    pub const NAMES: [&'static str; 3] = ["ringbuf", "perfbuf", "mappoll"];
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "ringbuf" => Ok(Self::Ringbuf),
+            "perfbuf" => Ok(Self::Perfbuf),
+            "mappoll" => Ok(Self::Mappoll),
+            _ => Err(anyhow!("Unknown transport {s}")),
+        }
+    }
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::NAMES[*self as usize])
+    }
+}
+
+/// A probe's output buffer, polled by a [`crate::poller::Poller`] or on the
+/// calling thread.
+pub enum Output {
+    Ringbuf(RingBuffer<'static>),
+    Perfbuf {
+        buf: PerfBuffer<'static>,
+        /// Samples the kernel dropped because a CPU's buffer was full.
+        lost: Arc<AtomicU64>,
+    },
+}
+
+impl Output {
+    /// Builds a perf buffer over `map` that hands samples of `record_size`
+    /// byte records to `callback`.
+    pub fn perf_buffer(
+        map: &Map,
+        record_size: usize,
+        mut callback: SampleCallback,
+    ) -> Result<Self> {
+        let lost = Arc::new(AtomicU64::new(0));
+        let lost_cb = lost.clone();
+        let buf = PerfBufferBuilder::new(map)
+            .sample_cb(move |_cpu, data: &[u8]| {
+                // Perf pads samples to 8 bytes after their size header
+                let len = data.len() - data.len() % record_size;
+                callback(&data[..len]);
+            })
+            .lost_cb(move |cpu, count| {
+                log::warn!("perf buffer of CPU {cpu} lost {count} samples");
+                lost_cb.fetch_add(count, Relaxed);
+            })
+            .build()?;
+        Ok(Self::Perfbuf { buf, lost })
+    }
+
+    /// Epoll instance over the buffer's fds; readable once there are samples
+    /// to consume.
+    pub fn epoll_fd(&self) -> RawFd {
+        match self {
+            Self::Ringbuf(rb) => rb.epoll_fd(),
+            Self::Perfbuf { buf, .. } => buf.epoll_fd(),
+        }
+    }
+
+    /// Waits up to `timeout` for samples and hands them to the callback, for
+    /// consuming on the calling thread.
+    pub fn poll(&self, timeout: Duration) -> libbpf_rs::Result<()> {
+        match self {
+            Self::Ringbuf(rb) => rb.poll(timeout),
+            Self::Perfbuf { buf, .. } => buf.poll(timeout),
+        }
+    }
+
+    pub fn consume(&self) -> libbpf_rs::Result<()> {
+        match self {
+            Self::Ringbuf(rb) => rb.consume(),
+            Self::Perfbuf { buf, .. } => buf.consume(),
+        }
+    }
+
+    /// Counter of the samples lost by a perf buffer; ring buffer drops are
+    /// counted in the kernel instead.
+    pub fn lost_samples(&self) -> Option<Arc<AtomicU64>> {
+        match self {
+            Self::Ringbuf(_) => None,
+            Self::Perfbuf { lost, .. } => Some(lost.clone()),
+        }
+    }
+}
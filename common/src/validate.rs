@@ -0,0 +1,250 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fmt::Display,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::{
+    bpf_errors::BpfErrors,
+    bpf_stats::BpfProgram,
+    bpf_structs::{PreadQueryRecord, RawPreadRecord},
+    probe::RunOptions,
+    window::{Panes, PreadQueryAgg, WindowSpec},
+};
+
+/// Runs an in-kernel probe alongside the userspace reference query until the
+/// flag is set and compares their windows, accepting count differences up to
+/// the slack.
+pub type Validator = fn(Arc<AtomicBool>, &RunOptions, u64) -> Result<Validation>;
+
+/// Results of one window, keyed by (fd, cpu).
+type Window = BTreeMap<(u64, u64), PreadQueryRecord>;
+
+/// Userspace reference implementation of the pread query: count, max and
+/// average of the pread size per (fd, cpu), over tumbling windows closed by
+/// [`Panes`] with the same semantics as the in-kernel probes.
+pub struct ReferenceQuery {
+    panes: Panes<(u64, u64), PreadQueryAgg>,
+    windows: Vec<Window>,
+}
+
+impl ReferenceQuery {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            panes: Panes::new(WindowSpec::Tumbling(window)),
+            windows: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, r: &RawPreadRecord) {
+        if let Some(closed) = self.panes.add(r.time, (r.fd, r.cpu), r.count) {
+            let window = closed
+                .groups
+                .iter()
+                .map(|(key, agg)| (*key, agg.record(*key)))
+                .collect();
+            self.windows.push(window);
+        }
+    }
+
+    /// Completed windows, oldest first.
+    pub fn windows(&self) -> &[Window] {
+        &self.windows
+    }
+}
+
+/// Puts the in-kernel probe's rows together into windows by their window start,
+/// oldest first. A window's rows can come in several samples: the opt probe
+/// sends one per CPU, at different times, and the perf buffer splits large
+/// windows.
+pub fn kernel_windows(
+    rows: impl IntoIterator<Item = PreadQueryRecord>,
+) -> Vec<Vec<PreadQueryRecord>> {
+    let mut windows: BTreeMap<u64, Vec<PreadQueryRecord>> = BTreeMap::new();
+    for row in rows {
+        windows.entry(row.window_start).or_default().push(row);
+    }
+    windows.into_values().collect()
+}
+
+/// A group whose in-kernel result disagrees with the reference.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub window: usize,
+    pub reason: &'static str,
+    pub kernel: Option<PreadQueryRecord>,
+    pub reference: Option<PreadQueryRecord>,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |r: &Option<PreadQueryRecord>| r.map_or("-".to_string(), |r| r.to_string());
+        write!(
+            f,
+            "window {}: {}\tkernel: {}\treference: {}",
+            self.window,
+            self.reason,
+            show(&self.kernel),
+            show(&self.reference)
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Validation {
+    pub kernel_windows: usize,
+    pub reference_windows: usize,
+    pub windows_compared: usize,
+    pub groups_compared: usize,
+    /// Result rows the in-kernel probe produced.
+    pub kernel_rows: usize,
+    pub mismatches: Vec<Mismatch>,
+    /// Runtime stats of the in-kernel probe's programs.
+    pub prog_stats: Vec<BpfProgram>,
+    /// Errors counted by the in-kernel probe's programs.
+    pub bpf_errors: BpfErrors,
+}
+
+impl Validation {
+    /// Compares the windows pairwise in order. The first window is skipped,
+    /// since the two probes attach at slightly different times, as are windows
+    /// only one side has completed.
+    ///
+    /// The probes timestamp each pread separately, so an event right at a
+    /// window boundary can land in different windows. Groups whose counts
+    /// differ by at most `slack` are accepted, and max/avg are only compared
+    /// when the counts agree exactly.
+    pub fn compare(kernel: &[Vec<PreadQueryRecord>], reference: &[Window], slack: u64) -> Self {
+        let mut v = Self {
+            kernel_windows: kernel.len(),
+            reference_windows: reference.len(),
+            kernel_rows: kernel.iter().map(Vec::len).sum(),
+            ..Default::default()
+        };
+        for (window, (k, r)) in kernel.iter().zip(reference).enumerate().skip(1) {
+            v.windows_compared += 1;
+            v.compare_window(window, k, r, slack);
+        }
+        v
+    }
+
+    fn compare_window(
+        &mut self,
+        window: usize,
+        kernel: &[PreadQueryRecord],
+        reference: &Window,
+        slack: u64,
+    ) {
+        let mut mismatch = |reason, kernel, reference| {
+            self.mismatches.push(Mismatch {
+                window,
+                reason,
+                kernel,
+                reference,
+            })
+        };
+
+        let mut groups = Window::new();
+        for k in kernel {
+            match groups.entry((k.fd, k.cpu)) {
+                Entry::Vacant(e) => {
+                    e.insert(*k);
+                }
+                Entry::Occupied(_) => mismatch("duplicate group", Some(*k), None),
+            }
+        }
+
+        for (key, r) in reference {
+            let Some(k) = groups.remove(key) else {
+                if r.count > slack {
+                    mismatch("missing from kernel", None, Some(*r));
+                }
+                continue;
+            };
+            self.groups_compared += 1;
+            let reason = if k.count.abs_diff(r.count) > slack {
+                Some("count")
+            } else if k.count != r.count {
+                None
+            } else if k.max_count != r.max_count {
+                Some("max")
+            } else if k.avg_count != r.avg_count {
+                Some("avg")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                mismatch(reason, Some(k), Some(*r));
+            }
+        }
+        for k in groups.into_values() {
+            if k.count > slack {
+                mismatch("missing from reference", Some(k), None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(time: u64, fd: u64, cpu: u64, count: u64) -> RawPreadRecord {
+        RawPreadRecord {
+            time,
+            fd,
+            cpu,
+            count,
+        }
+    }
+
+    fn row(window_start: u64, fd: u64, cpu: u64, count: u64, size: u64) -> PreadQueryRecord {
+        PreadQueryRecord {
+            fd,
+            cpu,
+            count,
+            max_count: size,
+            avg_count: size,
+            window_start,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn compares_windows_sent_in_several_samples() {
+        let mut reference = ReferenceQuery::new(Duration::from_nanos(100));
+        for r in [
+            raw(0, 3, 0, 8),
+            raw(100, 3, 0, 8),
+            raw(150, 3, 1, 8),
+            raw(200, 3, 0, 8),
+            raw(210, 3, 1, 8),
+            raw(300, 3, 0, 8),
+        ] {
+            reference.add(&r);
+        }
+        assert_eq!(reference.windows().len(), 3);
+
+        // One sample per CPU and window, as the opt probe sends them; CPU 1
+        // closes its second window late
+        let samples = [
+            vec![row(1000, 3, 0, 1, 8)],
+            vec![row(1100, 3, 0, 1, 8)],
+            vec![row(1100, 3, 1, 1, 8)],
+            vec![row(1200, 3, 0, 1, 8)],
+            vec![row(1200, 3, 1, 1, 8)],
+        ];
+        let windows = kernel_windows(samples.concat());
+        assert_eq!(windows.len(), 3);
+        let v = Validation::compare(&windows, reference.windows(), 0);
+        assert_eq!((v.windows_compared, v.groups_compared), (2, 4));
+        assert!(v.mismatches.is_empty(), "{:?}", v.mismatches);
+
+        // Each sample taken as a window of its own doesn't line up
+        let v = Validation::compare(&samples, reference.windows(), 0);
+        assert!(!v.mismatches.is_empty());
+    }
+}
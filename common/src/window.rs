@@ -0,0 +1,475 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    mem,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    aggs::{self, DISTINCT_BITS, SIZE_BUCKETS},
+    bpf_structs::PreadQueryRecord,
+};
+
+/// Per-group state of a windowed aggregation.
+pub trait Aggregate: Default {
+    type Value;
+
+    fn add(&mut self, value: Self::Value);
+}
+
+/// Per-group state that can be combined, e.g. the panes of a sliding window.
+pub trait Merge {
+    fn merge(&mut self, other: &Self);
+}
+
+/// Aggregates of the pread size for the pread query: count, max and average,
+/// plus the optional [`Aggs`](crate::aggs::Aggs) columns, which userspace
+/// always computes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PreadQueryAgg {
+    pub count: u64,
+    pub max: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub sum_sq: u64,
+    pub distinct_sketch: [u64; DISTINCT_BITS / 64],
+    pub hist: [u64; SIZE_BUCKETS],
+}
+
+impl Aggregate for PreadQueryAgg {
+    type Value = u64;
+
+    fn add(&mut self, value: u64) {
+        self.min = if self.count == 0 {
+            value
+        } else {
+            self.min.min(value)
+        };
+        self.count += 1;
+        self.max = self.max.max(value);
+        // Squares of sizes from 2^32 up overflow; the sums saturate instead, as
+        // they do in the BPF programs
+        self.sum = self.sum.saturating_add(value);
+        self.sum_sq = self.sum_sq.saturating_add(value.saturating_mul(value));
+        let bit = aggs::distinct_bit(value);
+        self.distinct_sketch[bit / 64] |= 1 << (bit % 64);
+        self.hist[aggs::size_bucket(value)] += 1;
+    }
+}
+
+impl Merge for PreadQueryAgg {
+    fn merge(&mut self, other: &Self) {
+        if other.count == 0 {
+            return;
+        }
+        self.min = if self.count == 0 {
+            other.min
+        } else {
+            self.min.min(other.min)
+        };
+        self.count += other.count;
+        self.max = self.max.max(other.max);
+        self.sum = self.sum.saturating_add(other.sum);
+        self.sum_sq = self.sum_sq.saturating_add(other.sum_sq);
+        for (a, b) in self.distinct_sketch.iter_mut().zip(other.distinct_sketch) {
+            *a |= b;
+        }
+        for (a, b) in self.hist.iter_mut().zip(other.hist) {
+            *a += b;
+        }
+    }
+}
+
+impl Merge for PreadQueryRecord {
+    fn merge(&mut self, other: &Self) {
+        PreadQueryRecord::merge(self, other)
+    }
+}
+
+impl PreadQueryAgg {
+    /// Finalized result row for the group `(fd, cpu)`.
+    pub fn record(&self, (fd, cpu): (u64, u64)) -> PreadQueryRecord {
+        PreadQueryRecord {
+            fd,
+            cpu,
+            count: self.count,
+            max_count: self.max,
+            avg_count: self.sum.checked_div(self.count).unwrap_or(0),
+            min_count: self.min,
+            sum_count: self.sum,
+            sum_sq_count: self.sum_sq,
+            distinct_sketch: self.distinct_sketch,
+            count_hist: self.hist,
+            ..Default::default()
+        }
+    }
+}
+
+/// Groups of a closed window, which covered `[start, end)` in nanoseconds.
+#[derive(Debug)]
+pub struct ClosedWindow<K, V> {
+    pub start: u64,
+    pub end: u64,
+    pub groups: BTreeMap<K, V>,
+}
+
+impl ClosedWindow<(u64, u64), PreadQueryAgg> {
+    pub fn records(&self) -> Vec<PreadQueryRecord> {
+        self.groups.iter().map(|(k, agg)| agg.record(*k)).collect()
+    }
+}
+
+/// Window of a query. Sliding and count windows with a step are built from
+/// panes one step long: the kernel (or [`Panes`]) closes a pane every step and
+/// [`PaneMerger`] merges the last `size / step` of them into a window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowSpec {
+    /// Back-to-back windows of a fixed length.
+    Tumbling(Duration),
+    /// Windows of length `size`, one every `step`.
+    Sliding { size: Duration, step: Duration },
+    /// Windows of `size` events, one every `step` events.
+    Count { size: u64, step: u64 },
+    /// Windows that close once no event came for `gap`.
+    Session { gap: Duration },
+}
+
+impl WindowSpec {
+    /// Checks that the window is non-empty and its size a multiple of its step.
+    pub fn validate(&self) -> Result<()> {
+        let (size, step) = match *self {
+            Self::Tumbling(size) => (size.as_nanos(), size.as_nanos()),
+            Self::Sliding { size, step } => (size.as_nanos(), step.as_nanos()),
+            Self::Count { size, step } => (size as u128, step as u128),
+            Self::Session { gap } => (gap.as_nanos(), gap.as_nanos()),
+        };
+        if step == 0 {
+            bail!("Window length and step must be non-zero");
+        }
+        if step > size || !size.is_multiple_of(step) {
+            bail!("Window length must be a multiple of its step");
+        }
+        Ok(())
+    }
+
+    /// Panes merged into each window.
+    pub fn panes(&self) -> usize {
+        match *self {
+            Self::Sliding { size, step } => (size.as_nanos() / step.as_nanos()) as usize,
+            Self::Count { size, step } => (size / step) as usize,
+            Self::Tumbling(_) | Self::Session { .. } => 1,
+        }
+    }
+}
+
+/// Aggregates values per key into panes, closing them like the ebql window
+/// header does: time panes lie on a grid of the step from the first event and
+/// close on the first event past their end, skipping empty panes; a count pane
+/// closes once it holds a step's worth of events and another arrives; a session
+/// closes on the first event more than the gap after the previous one. The
+/// event that closes a pane opens the next one, which is also where a closed
+/// count pane or session ends. Events older than the current pane (e.g. another
+/// CPU's delayed event) are counted in it, since earlier panes have closed.
+#[derive(Debug)]
+pub struct Panes<K, V> {
+    spec: WindowSpec,
+    start: Option<u64>,
+    last: u64,
+    count: u64,
+    groups: BTreeMap<K, V>,
+}
+
+impl<K: Ord, V: Aggregate> Panes<K, V> {
+    pub fn new(spec: WindowSpec) -> Self {
+        Self {
+            spec,
+            start: None,
+            last: 0,
+            count: 0,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `value` to `key`'s group, first closing and returning the current
+    /// pane if the event at `time` ends it.
+    pub fn add(&mut self, time: u64, key: K, value: V::Value) -> Option<ClosedWindow<K, V>> {
+        let mut closed = None;
+        match self.start {
+            None => self.start = Some(time),
+            Some(start) if self.closes(start, time) => {
+                closed = self.close(time);
+                self.start = Some(match self.step() {
+                    Some(step) => start + (time - start) / step * step,
+                    None => time,
+                });
+                self.count = 0;
+            }
+            Some(_) => {}
+        }
+        self.count += 1;
+        self.last = time;
+        self.groups.entry(key).or_default().add(value);
+        closed
+    }
+
+    /// Closes the current pane early, e.g. on shutdown.
+    pub fn flush(&mut self) -> Option<ClosedWindow<K, V>> {
+        let closed = self.close(self.last + 1);
+        self.start = None;
+        self.count = 0;
+        closed
+    }
+
+    /// Step of time panes in nanoseconds.
+    fn step(&self) -> Option<u64> {
+        match self.spec {
+            WindowSpec::Tumbling(step) | WindowSpec::Sliding { step, .. } => {
+                Some(step.as_nanos() as u64)
+            }
+            WindowSpec::Count { .. } | WindowSpec::Session { .. } => None,
+        }
+    }
+
+    fn closes(&self, start: u64, time: u64) -> bool {
+        match self.spec {
+            WindowSpec::Tumbling(_) | WindowSpec::Sliding { .. } => {
+                time >= start + self.step().unwrap()
+            }
+            WindowSpec::Count { step, .. } => self.count >= step,
+            // Events from other CPUs can be older than the last one
+            WindowSpec::Session { gap } => time.saturating_sub(self.last) > gap.as_nanos() as u64,
+        }
+    }
+
+    /// Closes the current pane. Time panes end on the grid, the other kinds at
+    /// `end`.
+    fn close(&mut self, end: u64) -> Option<ClosedWindow<K, V>> {
+        let start = self.start?;
+        if self.groups.is_empty() {
+            return None;
+        }
+        Some(ClosedWindow {
+            start,
+            end: self.step().map_or(end, |step| start + step),
+            groups: mem::take(&mut self.groups),
+        })
+    }
+}
+
+/// Merges each closed pane with the ones before it into a window of the last
+/// `panes` panes. Until that many panes have closed, windows cover fewer. Empty
+/// panes are never closed, so for sliding windows panes that ended before the
+/// window began are dropped as well.
+#[derive(Debug)]
+pub struct PaneMerger<K, V> {
+    panes: usize,
+    /// Length of sliding windows in nanoseconds.
+    size: Option<u64>,
+    recent: VecDeque<ClosedWindow<K, V>>,
+}
+
+impl<K: Ord + Clone, V: Merge + Clone> PaneMerger<K, V> {
+    pub fn new(spec: WindowSpec) -> Self {
+        let panes = spec.panes();
+        assert!(panes > 0, "Windows need at least one pane");
+        let size = match spec {
+            WindowSpec::Sliding { size, .. } => Some(size.as_nanos() as u64),
+            _ => None,
+        };
+        Self {
+            panes,
+            size,
+            recent: VecDeque::with_capacity(panes),
+        }
+    }
+
+    pub fn push(&mut self, pane: ClosedWindow<K, V>) -> ClosedWindow<K, V> {
+        if self.recent.len() == self.panes {
+            self.recent.pop_front();
+        }
+        if let Some(size) = self.size {
+            let start = pane.end.saturating_sub(size);
+            while self.recent.front().is_some_and(|p| p.start < start) {
+                self.recent.pop_front();
+            }
+        }
+        self.recent.push_back(pane);
+        let mut groups: BTreeMap<K, V> = BTreeMap::new();
+        for pane in &self.recent {
+            for (key, value) in &pane.groups {
+                match groups.get_mut(key) {
+                    Some(merged) => merged.merge(value),
+                    None => {
+                        groups.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        ClosedWindow {
+            start: self.recent.front().unwrap().start,
+            end: self.recent.back().unwrap().end,
+            groups,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tumbling() -> Panes<(u64, u64), PreadQueryAgg> {
+        Panes::new(WindowSpec::Tumbling(Duration::from_nanos(100)))
+    }
+
+    #[test]
+    fn tumbles_on_explicit_boundaries() {
+        let mut agg = tumbling();
+        assert!(agg.add(10, (3, 0), 8).is_none());
+        assert!(agg.add(109, (3, 0), 16).is_none());
+        let closed = agg.add(110, (3, 0), 4).unwrap();
+        assert_eq!((closed.start, closed.end), (10, 110));
+        let r = closed.records()[0];
+        assert_eq!(
+            (r.fd, r.cpu, r.count, r.max_count, r.avg_count),
+            (3, 0, 2, 16, 12)
+        );
+        assert_eq!((r.min_count, r.sum_count, r.sum_sq_count), (8, 24, 320));
+        assert_eq!((r.count_hist[3], r.count_hist[4]), (1, 1));
+        assert_eq!(r.variance(), 16.0);
+
+        // Skips the empty windows [210, 310) and [310, 410).
+        let closed = agg.add(450, (4, 1), 1).unwrap();
+        assert_eq!((closed.start, closed.end), (110, 210));
+        let closed = agg.flush().unwrap();
+        assert_eq!((closed.start, closed.end), (410, 510));
+    }
+
+    #[test]
+    fn large_values_saturate() {
+        let mut agg = tumbling();
+        let big = 1 << 40;
+        agg.add(0, (3, 0), big);
+        agg.add(1, (3, 0), big);
+        let mut r = agg.flush().unwrap().records()[0];
+        assert_eq!(
+            (r.sum_count, r.sum_sq_count, r.avg_count),
+            (2 * big, u64::MAX, big)
+        );
+
+        // Totals stay exact as long as they fit
+        let other = r;
+        r.merge(&other);
+        assert_eq!((r.count, r.avg_count, r.sum_count), (4, big, 4 * big));
+        assert_eq!(r.sum_sq_count, u64::MAX);
+    }
+
+    #[test]
+    fn flush_emits_partial_window() {
+        let mut agg = tumbling();
+        agg.add(0, (3, 0), 8);
+        agg.add(50, (3, 1), 8);
+        let closed = agg.flush().unwrap();
+        assert_eq!(closed.groups.len(), 2);
+        assert!(agg.flush().is_none());
+    }
+
+    fn panes(spec: WindowSpec) -> Panes<u64, PreadQueryAgg> {
+        Panes::new(spec)
+    }
+
+    /// A window's start, end and per-key counts.
+    type Counts = (u64, u64, Vec<(u64, u64)>);
+
+    /// Feeds `(time, key, value)` events through panes and a merger.
+    fn run(spec: WindowSpec, events: &[(u64, u64, u64)]) -> Vec<Counts> {
+        let mut panes = panes(spec);
+        let mut merger = PaneMerger::new(spec);
+        let mut windows = vec![];
+        let mut emit = |pane| {
+            let w: ClosedWindow<u64, PreadQueryAgg> = merger.push(pane);
+            let counts = w.groups.iter().map(|(k, a)| (*k, a.count)).collect();
+            windows.push((w.start, w.end, counts));
+        };
+        for &(time, key, value) in events {
+            if let Some(pane) = panes.add(time, key, value) {
+                emit(pane);
+            }
+        }
+        if let Some(pane) = panes.flush() {
+            emit(pane);
+        }
+        windows
+    }
+
+    #[test]
+    fn sliding_windows_merge_recent_panes() {
+        let spec = WindowSpec::Sliding {
+            size: Duration::from_nanos(200),
+            step: Duration::from_nanos(100),
+        };
+        let events = [(0, 1, 8), (50, 2, 8), (150, 1, 8), (260, 1, 8), (500, 2, 8)];
+        assert_eq!(
+            run(spec, &events),
+            vec![
+                // Pane [0, 100) alone
+                (0, 100, vec![(1, 1), (2, 1)]),
+                // Panes [0, 100) and [100, 200)
+                (0, 200, vec![(1, 2), (2, 1)]),
+                // Panes [100, 200) and [200, 300)
+                (100, 300, vec![(1, 2)]),
+                // The flushed pane [500, 600) alone: [200, 300) is older
+                // than the window [400, 600)
+                (500, 600, vec![(2, 1)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn count_windows_close_after_step_events() {
+        let spec = WindowSpec::Count { size: 4, step: 2 };
+        let events: Vec<_> = (0..6).map(|i| (i * 10, i % 2, 8)).collect();
+        let windows = run(spec, &events);
+        let counts: Vec<_> = windows.iter().map(|(.., c)| c.clone()).collect();
+        assert_eq!(
+            counts,
+            vec![
+                vec![(0, 1), (1, 1)],
+                vec![(0, 2), (1, 2)],
+                vec![(0, 2), (1, 2)],
+            ]
+        );
+        assert_eq!((windows[1].0, windows[1].1), (0, 40));
+    }
+
+    #[test]
+    fn session_windows_close_on_gaps() {
+        let spec = WindowSpec::Session {
+            gap: Duration::from_nanos(50),
+        };
+        let events = [(0, 1, 8), (40, 1, 8), (90, 1, 8), (141, 2, 8), (150, 2, 8)];
+        assert_eq!(
+            run(spec, &events),
+            vec![(0, 141, vec![(1, 3)]), (141, 151, vec![(2, 2)])]
+        );
+
+        // An event older than the last one stays in the session
+        let events = [(0, 1, 8), (40, 1, 8), (30, 1, 8)];
+        assert_eq!(run(spec, &events), vec![(0, 31, vec![(1, 3)])]);
+    }
+
+    #[test]
+    fn window_spec_needs_whole_panes() {
+        let sliding = |size, step| {
+            WindowSpec::Sliding {
+                size: Duration::from_millis(size),
+                step: Duration::from_millis(step),
+            }
+        };
+        assert!(sliding(1000, 250).validate().is_ok());
+        assert_eq!(sliding(1000, 250).panes(), 4);
+        assert!(sliding(1000, 300).validate().is_err());
+        assert!(sliding(100, 200).validate().is_err());
+        assert!(WindowSpec::Count { size: 0, step: 0 }.validate().is_err());
+    }
+}
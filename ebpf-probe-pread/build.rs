@@ -1,35 +1,92 @@
-use std::{env, path::PathBuf};
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use libbpf_cargo::SkeletonBuilder;
+use libbpf_rs::{
+    btf::{
+        types::{Composite, MemberAttr},
+        BtfType, HasSize,
+    },
+    Btf,
+};
 
 const VMLINUX: &str = "../bpf";
 const SRC: &str = "src/bpf";
 const UNOPT_DIR: &str = "unopt";
 const OPT_DIR: &str = "opt";
 const EBQL_DIR: &str = "ebql";
+const LATENCY_DIR: &str = "latency";
+const SYSCALL_DIR: &str = "syscall";
+const PERCPU_DIR: &str = "percpu";
 const BPF_SRC: &str = "pread_query.bpf.c";
-const OUT_SRC: &str = "pread_query.skel.rs";
+const OUT_LAYOUTS: &str = "record_layouts.rs";
+
+/// Record type each object submits to userspace, or leaves in a map for it to
+/// read: (dir, C type, Rust type).
+const RECORDS: &[(&str, &str, &str)] = &[
+    (EBQL_DIR, "pread_query_t", "PreadQueryRow"),
+    (EBQL_DIR, "pread_query_wide_t", "PreadQueryWideRow"),
+    (EBQL_DIR, "group_by_pread_query_t", "PreadGroupBy"),
+    (EBQL_DIR, "avg_t", "AvgAgg"),
+    (EBQL_DIR, "ext_agg_t", "ExtAgg"),
+    (OPT_DIR, "pread_query_t", "PreadQueryRow"),
+    (OPT_DIR, "pread_query_wide_t", "PreadQueryWideRow"),
+    (UNOPT_DIR, "raw_pread_t", "RawPreadRecord"),
+    (LATENCY_DIR, "pread_latency_t", "PreadLatencyRecord"),
+    (SYSCALL_DIR, "syscall_query_t", "SyscallQueryRecord"),
+    (PERCPU_DIR, "window_group_t", "PercpuPreadKey"),
+    (PERCPU_DIR, "percpu_agg_t", "PercpuPreadAgg"),
+];
+
+/// BPF source of each probe dir; the skeleton is `<dir>_<stem>.skel.rs`.
+fn bpf_src(dir: &str) -> &'static str {
+    match dir {
+        OPT_DIR => "pread_query_next.bpf.c",
+        LATENCY_DIR => "pread_latency.bpf.c",
+        SYSCALL_DIR => "syscall_query.bpf.c",
+        _ => BPF_SRC,
+    }
+}
 
 fn main() {
+    let out_dir =
+        PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR must be set in build script"));
     let mut srcs = vec![];
-    for dir in [EBQL_DIR, OPT_DIR, UNOPT_DIR] {
-        let mut out =
-            PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR must be set in build script"));
-        out.push(format!("{}_{}", dir, OUT_SRC));
-
-        // let src: PathBuf = [SRC, dir, BPF_SRC].iter().collect();
-        let mut src: PathBuf = [SRC, dir, BPF_SRC].iter().collect();
-        if dir == OPT_DIR {
-            src = [SRC, dir, "pread_query_next.bpf.c"].iter().collect();
-        }
+    let mut layouts = String::from(
+        "// Generated by build.rs from the BTF of the compiled BPF objects; fails to compile if a\n\
+         // Rust record no longer matches the struct its BPF program submits.\n",
+    );
+    for dir in [
+        EBQL_DIR,
+        OPT_DIR,
+        UNOPT_DIR,
+        LATENCY_DIR,
+        SYSCALL_DIR,
+        PERCPU_DIR,
+    ] {
+        let stem = bpf_src(dir).trim_end_matches(".bpf.c");
+        let out = out_dir.join(format!("{dir}_{stem}.skel.rs"));
+        let obj = out_dir.join(format!("{dir}_{stem}.bpf.o"));
+
+        let src: PathBuf = [SRC, dir, bpf_src(dir)].iter().collect();
         srcs.push(src.clone());
 
         SkeletonBuilder::new()
             .source(src)
+            .obj(&obj)
             .clang_args([format!("-I{VMLINUX}")])
             .build_and_generate(out)
             .expect("bpf compilation failed");
+
+        for (_, c_ty, rust_ty) in RECORDS.iter().filter(|(d, ..)| *d == dir) {
+            layout_asserts(&mut layouts, dir, &obj, c_ty, rust_ty);
+        }
     }
+    fs::write(out_dir.join(OUT_LAYOUTS), layouts).expect("failed to write record layouts");
 
     for src in srcs {
         println!(
@@ -37,4 +94,44 @@ fn main() {
             src.as_os_str().to_str().unwrap()
         );
     }
+    println!("cargo:rerun-if-changed={SRC}");
+}
+
+/// Appends compile-time asserts that `rust_ty` has the size and field offsets
+/// of `c_ty` in the BTF of `obj`. C fields named with a trailing underscore to
+/// dodge reserved words (e.g. `count_`) map to the Rust field without it.
+fn layout_asserts(out: &mut String, dir: &str, obj: &Path, c_ty: &str, rust_ty: &str) {
+    let btf = Btf::from_path(obj).expect("failed to parse BTF of bpf object");
+    let ty = btf
+        .type_by_name::<BtfType<'_>>(c_ty)
+        .unwrap_or_else(|| panic!("{dir}: no BTF for {c_ty}; is it anchored in the bpf source?"))
+        .skip_mods_and_typedefs();
+    let composite =
+        Composite::try_from(ty).unwrap_or_else(|_| panic!("{dir}: {c_ty} is not a struct"));
+
+    let what = format!("{dir} {c_ty} vs {rust_ty}");
+    writeln!(
+        out,
+        "const _: () = assert!(std::mem::size_of::<{rust_ty}>() == {}, \"{what}: size mismatch\");",
+        composite.size()
+    )
+    .unwrap();
+    for member in composite.iter() {
+        let name = member
+            .name
+            .unwrap_or_else(|| panic!("{dir}: {c_ty} has an anonymous member"))
+            .to_str()
+            .unwrap();
+        let offset = match member.attr {
+            MemberAttr::Normal { offset } => offset / 8,
+            MemberAttr::BitField { .. } => panic!("{dir}: {c_ty}.{name} is a bitfield"),
+        };
+        let field = name.strip_suffix('_').unwrap_or(name);
+        writeln!(
+            out,
+            "const _: () = assert!(std::mem::offset_of!({rust_ty}, {field}) == {offset}, \
+             \"{what}: offset of {name} mismatch\");"
+        )
+        .unwrap();
+    }
 }
@@ -7,17 +7,13 @@
 #include "common.bpf.h"
 #include "pread_query.bpf.h"
 
-// Depending on group by key, can reduce number of max entries (e.g. for cpu, only need # cpus)
+// Depending on group by key, can reduce number of max entries (e.g. for cpu, only need # cpus).
+// Default only; userspace resizes the maps before load.
 #define AGG_MAX_ENTRIES (16384)
 
 // Since BPF doesn't allow FP, scale values by AVG_SCALE (4 -> +4 sigfigs)
 #define AVG_SCALE (1000000)
 
-typedef struct {
-  u64 fd;
-  u64 cpu;
-} group_by_pread_query_t;
-
 // Avg counter for individual item.
 typedef struct {
   // Note: the averaged value doesn't have to be u64, but do this to prevent
@@ -39,53 +35,102 @@ static __always_inline void min(agg_t *agg, u64 val) {
   if (val < agg->val) agg->val = val;
 }
 static __always_inline void count(agg_t *agg, u64 val) { agg->val += 1; }
-static __always_inline void sum(agg_t *agg, u64 val) { agg->val += val; }
+// Sums saturate rather than wrap, as in userspace
+static __always_inline void sum(agg_t *agg, u64 val) { agg->val = sat_add_u64(agg->val, val); }
 static __always_inline void avg(avg_t *agg, u64 val) {
-  agg->val += val;
+  agg->val = sat_add_u64(agg->val, val);
   agg->count += 1;
 }
 
+// The maps are keyed on group_by_pread_query_t up to its last selected field;
+// userspace shrinks the key size before load, so the default group by keys on
+// fd and cpu alone
 struct {
   __uint(type, BPF_MAP_TYPE_HASH);
-  __type(key, group_by_pread_query_t);
+  __uint(key_size, sizeof(group_by_pread_query_t));
   __type(value, agg_t);
   __uint(max_entries, AGG_MAX_ENTRIES);
   __uint(map_flags, BPF_F_NO_PREALLOC);
 } count__pread_query SEC(".maps");
 struct {
   __uint(type, BPF_MAP_TYPE_HASH);
-  __type(key, group_by_pread_query_t);
+  __uint(key_size, sizeof(group_by_pread_query_t));
   __type(value, agg_t);
   __uint(max_entries, AGG_MAX_ENTRIES);
   __uint(map_flags, BPF_F_NO_PREALLOC);
 } max_count_pread_query SEC(".maps");
 struct {
   __uint(type, BPF_MAP_TYPE_HASH);
-  __type(key, group_by_pread_query_t);
+  __uint(key_size, sizeof(group_by_pread_query_t));
   __type(value, avg_t);
   __uint(max_entries, AGG_MAX_ENTRIES);
   __uint(map_flags, BPF_F_NO_PREALLOC);
 } avg_count_pread_query SEC(".maps");
+// Optional aggregates; only updated if any are selected in AGGS
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(key_size, sizeof(group_by_pread_query_t));
+  __type(value, ext_agg_t);
+  __uint(max_entries, AGG_MAX_ENTRIES);
+  __uint(map_flags, BPF_F_NO_PREALLOC);
+} ext_count_pread_query SEC(".maps");
+
+// Second set of the maps above. With map polling, preads go to one set while
+// userspace drains the other, so every drain sees whole preads
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(key_size, sizeof(group_by_pread_query_t));
+  __type(value, agg_t);
+  __uint(max_entries, AGG_MAX_ENTRIES);
+  __uint(map_flags, BPF_F_NO_PREALLOC);
+} count__pread_query_1 SEC(".maps");
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(key_size, sizeof(group_by_pread_query_t));
+  __type(value, agg_t);
+  __uint(max_entries, AGG_MAX_ENTRIES);
+  __uint(map_flags, BPF_F_NO_PREALLOC);
+} max_count_pread_query_1 SEC(".maps");
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(key_size, sizeof(group_by_pread_query_t));
+  __type(value, avg_t);
+  __uint(max_entries, AGG_MAX_ENTRIES);
+  __uint(map_flags, BPF_F_NO_PREALLOC);
+} avg_count_pread_query_1 SEC(".maps");
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(key_size, sizeof(group_by_pread_query_t));
+  __type(value, ext_agg_t);
+  __uint(max_entries, AGG_MAX_ENTRIES);
+  __uint(map_flags, BPF_F_NO_PREALLOC);
+} ext_count_pread_query_1 SEC(".maps");
 
-static __always_inline s32 insert_count__pread_query(group_by_pread_query_t key, u64 val) {
-  s32 ret;
-  agg_t *agg = (agg_t *)bpf_map_lookup_elem(&count__pread_query, &key);
+// Map `name` of the first set, or of the second if `second`
+#define AGG_MAP(name, second) ((second) ? (void *)&name##_1 : (void *)&name)
+
+static __always_inline s32 insert_count__pread_query(void *map, group_by_pread_query_t key, u64 val) {
+  s32 ret = 0;
+  agg_t *agg = (agg_t *)bpf_map_lookup_elem(map, &key);
   if (!agg) {
     agg_t init = {val};
-    ret = bpf_map_update_elem(&count__pread_query, &key, &init, BPF_NOEXIST);
+    ret = bpf_map_update_elem(map, &key, &init, BPF_NOEXIST);
   } else {
     count(agg, val);
   }
   if (ret != 0) {
     ERROR("failed to insert into count map: %d", ret);
+    count_error(E_MAP_FULL, 1);
   }
   return ret;
 }
 
 typedef struct {
-  pread_query_t *buf;
+  // Rows of ROW_BYTES each
+  void *buf;
   u64 buf_sz;
   u64 count;
+  u64 window_start;
 } count__pread_query_ctx_t;
 
 static __always_inline s64 __get_count__pread_query_callback(struct bpf_map *map,
@@ -106,15 +151,26 @@ static __always_inline s64 __get_count__pread_query_callback(struct bpf_map *map
     WARN("Number of aggregation results exceeds buf size; stopping...");
     return 1;
   }
-  ctx->buf[ctx->count].fd = key->fd;
-  ctx->buf[ctx->count].cpu = key->cpu;
-  ctx->buf[ctx->count].count_ = agg->val;
+  pread_query_t *row = row_at(ctx->buf, ctx->count);
+  set_group_by(row, key);
+  row->count_ = agg->val;
+  row->window_start = ctx->window_start;
+  // The other aggregates live in maps of their own, which iterate in their own
+  // order; look the group up there rather than going by position
+  agg_t *max_agg = bpf_map_lookup_elem(&max_count_pread_query, key);
+  row->max_count = max_agg ? max_agg->val : 0;
+  avg_t *avg_agg = bpf_map_lookup_elem(&avg_count_pread_query, key);
+  // Defer computation until here
+  row->avg_count = (avg_agg && avg_agg->count) ? avg_agg->val / avg_agg->count : 0;
+  ext_agg_t *ext = AGGS ? bpf_map_lookup_elem(&ext_count_pread_query, key) : NULL;
+  set_ext_agg(row, ext ? ext : &ext_agg_zero);
   ctx->count += 1;
   return 0;
 }
 
-static __always_inline void get_count__pread_query(pread_query_t *buf, u64 buf_sz) {
-  count__pread_query_ctx_t ctx = {.buf = buf, .buf_sz = buf_sz, .count = 0};
+static __always_inline void get_count__pread_query(void *buf, u64 buf_sz, u64 window_start) {
+  count__pread_query_ctx_t ctx = {
+      .buf = buf, .buf_sz = buf_sz, .count = 0, .window_start = window_start};
   bpf_for_each_map_elem(&count__pread_query, __get_count__pread_query_callback, &ctx, 0);
 }
 
@@ -149,57 +205,22 @@ static __always_inline void tumble_count__pread_query() {
   bpf_for_each_map_elem(&count__pread_query, __tumble_count__pread_query_callback, NULL, 0);
 }
 
-static __always_inline s32 insert_max_count_pread_query(group_by_pread_query_t key, u64 val) {
-  s32 ret;
-  agg_t *agg = (agg_t *)bpf_map_lookup_elem(&max_count_pread_query, &key);
+static __always_inline s32 insert_max_count_pread_query(void *map, group_by_pread_query_t key, u64 val) {
+  s32 ret = 0;
+  agg_t *agg = (agg_t *)bpf_map_lookup_elem(map, &key);
   if (!agg) {
     agg_t init = {val};
-    ret = bpf_map_update_elem(&max_count_pread_query, &key, &init, BPF_NOEXIST);
+    ret = bpf_map_update_elem(map, &key, &init, BPF_NOEXIST);
   } else {
     max(agg, val);
   }
   if (ret != 0) {
     ERROR("failed to insert into sum map: %d", ret);
+    count_error(E_MAP_FULL, 1);
   }
   return ret;
 }
 
-typedef struct {
-  pread_query_t *buf;
-  u64 buf_sz;
-  u64 count;
-} max_count_pread_query_ctx_t;
-
-static __always_inline s64 __get_max_count_pread_query_callback(struct bpf_map *map,
-                                                           group_by_pread_query_t *key,
-                                                           agg_t *agg,
-                                                           max_count_pread_query_ctx_t *ctx) {
-  // Skip if val is 0
-  // TODO: migrate from val -> add count to agg_t
-  if (agg->val == 0) {
-    return 0;
-  }
-  // Set agg value
-  if (!ctx || !ctx->buf) {
-    ERROR("Passed null context/context buffer in");
-    return 1;
-  }
-  if (ctx->count >= ctx->buf_sz) {
-    WARN("Number of aggregation results exceeds buf size; stopping...");
-    return 1;
-  }
-  ctx->buf[ctx->count].fd = key->fd;
-  ctx->buf[ctx->count].cpu = key->cpu;
-  ctx->buf[ctx->count].max_count = agg->val;
-  ctx->count += 1;
-  return 0;
-}
-
-static __always_inline void get_max_count_pread_query(pread_query_t *buf, u64 buf_sz) {
-  max_count_pread_query_ctx_t ctx = {.buf = buf, .buf_sz = buf_sz, .count = 0};
-  bpf_for_each_map_elem(&max_count_pread_query, __get_max_count_pread_query_callback, &ctx, 0);
-}
-
 static __always_inline u64 __count_max_count_pread_query_callback(struct bpf_map *map,
                                                              group_by_pread_query_t *key,
                                                              agg_t *agg,
@@ -231,60 +252,22 @@ static __always_inline void tumble_max_count_pread_query() {
   bpf_for_each_map_elem(&max_count_pread_query, __tumble_max_count_pread_query_callback, NULL, 0);
 }
 
-static __always_inline s32 insert_avg_count_pread_query(group_by_pread_query_t key, u64 val) {
-  s32 ret;
-  avg_t *agg = (avg_t *)bpf_map_lookup_elem(&avg_count_pread_query, &key);
+static __always_inline s32 insert_avg_count_pread_query(void *map, group_by_pread_query_t key, u64 val) {
+  s32 ret = 0;
+  avg_t *agg = (avg_t *)bpf_map_lookup_elem(map, &key);
   if (!agg) {
     avg_t init = {val, 1};
-    ret = bpf_map_update_elem(&avg_count_pread_query, &key, &init, BPF_NOEXIST);
+    ret = bpf_map_update_elem(map, &key, &init, BPF_NOEXIST);
   } else {
     avg(agg, val);
   }
   if (ret != 0) {
     ERROR("failed to insert into avg map: %d", ret);
+    count_error(E_MAP_FULL, 1);
   }
   return ret;
 }
 
-typedef struct {
-  pread_query_t *buf;
-  u64 buf_sz;
-  u64 count;
-} avg_count_pread_query_ctx_t;
-
-static __always_inline s64 __get_avg_count_pread_query_callback(struct bpf_map *map,
-                                                           group_by_pread_query_t *key,
-                                                           avg_t *agg,
-                                                           avg_count_pread_query_ctx_t *ctx) {
-  // Skip if val is 0
-  // TODO: migrate from val -> add count to agg_t
-  if (agg->val == 0) {
-    return 0;
-  }
-  // Set agg value
-  if (!ctx || !ctx->buf) {
-    ERROR("Passed null context/context buffer in");
-    return 1;
-  }
-  if (ctx->count >= ctx->buf_sz) {
-    WARN("Number of aggregation results exceeds buf size; stopping...");
-    return 1;
-  }
-  ctx->buf[ctx->count].fd = key->fd;
-  ctx->buf[ctx->count].cpu = key->cpu;
-  ctx->buf[ctx->count].avg_count = agg->val;
-  // Defer computation until here
-  ctx->buf[ctx->count].avg_count /= agg->count;
-  // ctx->buf[ctx->count].avg_count_count = agg->count;
-  ctx->count += 1;
-  return 0;
-}
-
-static __always_inline void get_avg_count_pread_query(pread_query_t *buf, u64 buf_sz) {
-  avg_count_pread_query_ctx_t ctx = {.buf = buf, .buf_sz = buf_sz, .count = 0};
-  bpf_for_each_map_elem(&avg_count_pread_query, __get_avg_count_pread_query_callback, &ctx, 0);
-}
-
 static __always_inline u64 __count_avg_count_pread_query_callback(struct bpf_map *map,
                                                              group_by_pread_query_t *key,
                                                              avg_t *agg,
@@ -317,3 +300,34 @@ static __always_inline void tumble_avg_count_pread_query() {
   bpf_for_each_map_elem(&avg_count_pread_query, __tumble_avg_count_pread_query_callback, NULL, 0);
 }
 
+static __always_inline s32 insert_ext_count_pread_query(void *map, group_by_pread_query_t key, u64 val) {
+  s32 ret = 0;
+  ext_agg_t *agg = (ext_agg_t *)bpf_map_lookup_elem(map, &key);
+  if (!agg) {
+    // Too large to build on the stack; insert zeroed and update in place
+    ret = bpf_map_update_elem(map, &key, &ext_agg_zero, BPF_NOEXIST);
+    if (ret != 0) {
+      ERROR("failed to insert into ext map: %d", ret);
+      count_error(E_MAP_FULL, 1);
+      return ret;
+    }
+    agg = (ext_agg_t *)bpf_map_lookup_elem(map, &key);
+    if (!agg) {
+      return 1;
+    }
+  }
+  ext_agg_add(agg, val);
+  return ret;
+}
+
+static __always_inline u64 __tumble_ext_count_pread_query_callback(struct bpf_map *map,
+                                                             group_by_pread_query_t *key,
+                                                             ext_agg_t *agg,
+                                                             void *ctx) {
+  __builtin_memset(agg, 0, sizeof(*agg));
+  return 0;
+}
+
+static __always_inline void tumble_ext_count_pread_query() {
+  bpf_for_each_map_elem(&ext_count_pread_query, __tumble_ext_count_pread_query_callback, NULL, 0);
+}
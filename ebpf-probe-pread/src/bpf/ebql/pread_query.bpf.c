@@ -6,16 +6,89 @@
 #include "agg_pread_query.bpf.h" /* External includes (agg) */
 
 
+// Instances so the row and key types are emitted in BTF; build.rs checks the
+// Rust records against them. The maps only have the key's size.
+pread_query_t _pread_query_t = {0};
+pread_query_wide_t _pread_query_wide_t = {0};
+group_by_pread_query_t _group_by_pread_query_t = {0};
+
 // *** MAPS SECTION *** //
+#define RB_MAX_ENTRIES (4194280)
 struct {
 	__uint(type, BPF_MAP_TYPE_RINGBUF);
-	__uint(max_entries, 4194280);
+	__uint(max_entries, RB_MAX_ENTRIES);
 } ring_buf_pread_query SEC(".maps");
+// Size of ring_buf_pread_query; set from userspace along with the map's size
+const volatile u64 RINGBUF_BYTES = RB_MAX_ENTRIES;
+
+// Rows of a window sent through perf_buf are staged here, in the entry of the
+// emitting CPU. Userspace sizes the map to the number of CPUs when the perf
+// buffer is used.
+#define PERF_SAMPLES 16
+#define PERF_MAX_ROWS (PERF_SAMPLES * (PERF_SAMPLE_MAX / ROW_BYTES))
+typedef struct {
+  u8 rows[PERF_SAMPLES * PERF_SAMPLE_MAX];
+} perf_rows_pread_query_t;
+struct {
+  __uint(type, BPF_MAP_TYPE_ARRAY);
+  __type(key, u32);
+  __type(value, perf_rows_pread_query_t);
+  __uint(max_entries, 1);
+} perf_rows_pread_query SEC(".maps");
+
+// Set of aggregation maps preads go to with map polling; userspace flips it
+// before draining the set preads went to until then
+u32 mappoll_set = 0;
 
 
 // *** CODE SECTION *** //
+// Emits the groups of the window that started at `start` and clears them
+static __always_inline u32 emit_pread_query(void* ctx, u64 start) {
+  u64 n_results = count_count__pread_query();
+  u64 max_results = (TRANSPORT == T_PERFBUF) ? PERF_MAX_ROWS
+                                             : RINGBUF_BYTES / ROW_BYTES;
+  if (n_results >= max_results) {
+    WARN("Got too many results; truncating to max rb entries...");
+    count_error(E_TRUNCATED, n_results - max_results);
+    n_results = max_results;
+  }
+  if (n_results > 0) {
+    void* buf;
+    if (TRANSPORT == T_PERFBUF) {
+      u32 cpu = bpf_get_smp_processor_id();
+      buf = bpf_map_lookup_elem(&perf_rows_pread_query, &cpu);
+      if (!buf) {
+        ERROR("No perf staging rows for CPU %u", cpu);
+        return 1;
+      }
+    } else {
+      buf = bpf_ringbuf_reserve(&ring_buf_pread_query, n_results * ROW_BYTES, 0);
+      if (!buf) {
+        ERROR("Failed to allocate from ring buffer");
+        count_error(E_RINGBUF_RESERVE, 1);
+        return 1;
+      }
+    }
+    get_count__pread_query(buf, n_results, start);
+    if (TRANSPORT == T_PERFBUF) {
+      perf_output_records(ctx, buf, ROW_BYTES, n_results, PERF_SAMPLES);
+    } else {
+      bpf_ringbuf_submit(buf, 0);
+    }
+  }
+  tumble_count__pread_query();
+  tumble_max_count_pread_query();
+  tumble_avg_count_pread_query();
+  if (AGGS) {
+    tumble_ext_count_pread_query();
+  }
+  return 0;
+}
+
 SEC("tp/syscalls/sys_enter_pread64")
 u32 pread_query(struct trace_event_raw_sys_enter* ctx) {
+	if (!filter_task())
+		return 0;
 	u64 time;
 	TIME(time);
 	u64 fd;
@@ -24,36 +97,42 @@ u32 pread_query(struct trace_event_raw_sys_enter* ctx) {
 	CPU(cpu);
 	u64 count;
 	count = ctx->args[2];
-	bool tumble = window_add(time);
+	// With map polling, userspace drains the maps and decides the windows
+	bool tumble = (TRANSPORT != T_MAPPOLL) && window_add(time);
   if (tumble) {
+    u64 start = w.start_time;
     window_tumble(time);
-    u64 n_results = count_count__pread_query();
-    if (n_results >= 104857) {
-      WARN("Got too many results; truncating to max rb entries...");
-      n_results = 104857;
-    }
-    if (n_results > 0) {
-      pread_query_t* buf =
-          bpf_ringbuf_reserve(&ring_buf_pread_query, n_results * sizeof(pread_query_t), 0);
-      if (!buf) {
-        ERROR("Failed to allocate from ring buffer");
-        return 1;
-      }
-      get_count__pread_query(buf, n_results);
-      get_max_count_pread_query(buf, n_results);
-      get_avg_count_pread_query(buf, n_results);
-      bpf_ringbuf_submit(buf, 0);
+    if (emit_pread_query(ctx, start)) {
+      return 1;
     }
-    tumble_count__pread_query();
-    tumble_max_count_pread_query();
-    tumble_avg_count_pread_query();
   }
-  insert_count__pread_query((group_by_pread_query_t){fd, cpu}, 1);
-	insert_max_count_pread_query((group_by_pread_query_t){fd, cpu}, count);
-	insert_avg_count_pread_query((group_by_pread_query_t){fd, cpu}, count);
+  group_by_pread_query_t gb = make_group_by(fd, cpu);
+  // Read once, so all of the pread's aggregates go to the same set
+  bool second = (TRANSPORT == T_MAPPOLL) && *(volatile u32*)&mappoll_set;
+  insert_count__pread_query(AGG_MAP(count__pread_query, second), gb, 1);
+	insert_max_count_pread_query(AGG_MAP(max_count_pread_query, second), gb, count);
+	insert_avg_count_pread_query(AGG_MAP(avg_count_pread_query, second), gb, count);
+	if (AGGS) {
+		insert_ext_count_pread_query(AGG_MAP(ext_count_pread_query, second), gb, count);
+	}
 	return 0;
 }
 
+// Closes the window if it is due, or whenever ctx[0] is set. Not attached;
+// userspace runs it through BPF_PROG_TEST_RUN so windows close on schedule
+// through quiet periods, and with ctx[0] set on shutdown.
+SEC("raw_tp")
+u32 flush_pread_query(u64* ctx) {
+  u64 time;
+  TIME(time);
+  if (w.start_time == 0 || !(ctx[0] || window_due(time))) {
+    return 0;
+  }
+  u64 start = w.start_time;
+  window_close(time);
+  return emit_pread_query(ctx, start);
+}
+
 
 // *** LICENSE *** //
 char LICENSE[] SEC("license") = "Dual BSD/GPL";
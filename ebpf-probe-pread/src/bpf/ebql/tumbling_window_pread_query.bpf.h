@@ -15,35 +15,123 @@
  * RESTRICTIONS (until I can figure out more verifier stuff):
  * - For counts, WINDOW_SIZE % STEP == 0 (i.e. WINDOW_SIZE must be divisible by
  * STEP)
- * - For time, STEP == INTERVAL (i.e. all time windows must be tumbling
- * windows).
+ * - For time, INTERVAL % STEP == 0.
+ * - Only panes of one step are kept here; userspace merges the panes of
+ * sliding windows (step < interval).
+ *
+ * Windows close on the first event past their end, or earlier when userspace
+ * runs the probe's flush program.
  */
 
 #include "common.bpf.h"
 #include "pread_query.bpf.h"
 
-#define INTERVAL (1000000000)
+// Window kind; must match the panes of WindowSpec on the Rust side. Sliding
+// and stepped count windows are emitted as panes one step long, which
+// userspace merges into windows.
+enum WINDOW_KIND { W_TIME = 0, W_COUNT, W_SESSION };
+
+// Window parameters; set from userspace before load. WINDOW_NS is the pane
+// length of time windows, WINDOW_COUNT the number of events in a count pane and
+// SESSION_GAP_NS the inactivity that closes a session.
+const volatile u8 WINDOW_KIND = W_TIME;
+const volatile u64 WINDOW_NS = 1000000000;
+const volatile u64 WINDOW_COUNT = 1000;
+const volatile u64 SESSION_GAP_NS = 1000000000;
 
-// Window representation: for tumbling windows over the aggregations currently supported, only need
-// count/time to know when to tumble.
+// Window representation: for the aggregations currently supported, only need
+// the start, the last event and the number of events to know when to tumble.
 typedef struct window {
   u64 start_time;
+  u64 last_time;
+  u64 count;
 } window_t;
 
 window_t w = {0};
 
 /**
- * Adds to window. Returns whether flushing is needed
+ * Start of the time pane that `time` falls in. Panes lie on a grid of
+ * WINDOW_NS from the first one, so their boundaries don't drift with the
+ * events (or flushes) that close them.
+ */
+static __always_inline u64 window_pane_start(u64 time) {
+  if (time < w.start_time) {
+    return w.start_time;
+  }
+  return w.start_time + (time - w.start_time) / WINDOW_NS * WINDOW_NS;
+}
+
+/**
+ * Whether the window is over at `time` without another event: time panes end
+ * WINDOW_NS after their start and sessions SESSION_GAP_NS after their last
+ * event. Count panes only end on events.
+ */
+static __always_inline bool window_due(u64 time) {
+  if (w.start_time == 0) {
+    return false;
+  }
+  if (WINDOW_KIND == W_TIME) {
+    return time >= w.start_time + WINDOW_NS;
+  }
+  if (WINDOW_KIND == W_SESSION) {
+    // Events from other CPUs can be older than the last one
+    return time > w.last_time && time - w.last_time > SESSION_GAP_NS;
+  }
+  return false;
+}
+
+/**
+ * Adds to window. Returns whether flushing is needed; if so, the event belongs
+ * to the next window, which window_tumble opens.
  */
 static __always_inline bool window_add(u64 time) {
+  bool tumble = false;
   if (w.start_time == 0) {
     w.start_time = time;
-    return false;
+  } else if (WINDOW_KIND == W_COUNT) {
+    tumble = w.count >= WINDOW_COUNT;
+  } else {
+    tumble = window_due(time);
+  }
+  w.last_time = time;
+  w.count += 1;
+  return tumble;
+}
+
+/**
+ * Moves a time window on to the pane holding `time`, counting the empty panes
+ * in between as skipped.
+ */
+static __always_inline void window_next_pane(u64 time) {
+  u64 start = window_pane_start(time);
+  if (start > w.start_time + WINDOW_NS) {
+    count_error(E_WINDOW_SKIP, (start - w.start_time) / WINDOW_NS - 1);
   }
-  return (w.start_time + INTERVAL < time);
+  w.start_time = start;
 }
 
 /**
- * Tumbles the window.
+ * Tumbles the window, opening the next one with the event that closed it:
+ * the time pane holding the event, or a count pane or session starting at it.
  */
-static __always_inline void window_tumble(u64 time) { w.start_time = time; }
+static __always_inline void window_tumble(u64 time) {
+  if (WINDOW_KIND == W_TIME) {
+    window_next_pane(time);
+  } else {
+    w.start_time = time;
+  }
+  w.count = 1;
+}
+
+/**
+ * Closes the window without an event, when flushing. Time panes move on to the
+ * pane holding `time`; the next event opens the other kinds.
+ */
+static __always_inline void window_close(u64 time) {
+  if (WINDOW_KIND == W_TIME) {
+    window_next_pane(time);
+  } else {
+    w.start_time = 0;
+  }
+  w.count = 0;
+}
@@ -0,0 +1,208 @@
+// *** SOURCE FOR pread_latency *** //
+//
+// Pairs sys_enter_pread64 with sys_exit_pread64 by thread to aggregate pread
+// latency and bytes read per (fd, cpu), over tumbling windows like the
+// pread_query probes.
+
+#include "common.bpf.h"
+
+// Must match LATENCY_BUCKETS on the Rust side
+#define LATENCY_BUCKETS 32
+
+// Emitted struct
+typedef struct {
+  u64 fd;
+  u64 cpu;
+  u64 count;
+  u64 errors;
+  u64 bytes;
+  u64 latency_sum_ns;
+  u64 latency_max_ns;
+  // Bucket i counts latencies in [2^i, 2^(i+1)) ns; the last one also counts
+  // everything above
+  u64 latency_hist[LATENCY_BUCKETS];
+} pread_latency_t;
+
+// Keeps pread_latency_t in BTF for the layout check in build.rs
+pread_latency_t _pread_latency_t = {0};
+
+// In-flight pread of a thread
+typedef struct {
+  u64 start;
+  u64 fd;
+} pread_start_t;
+
+// In-flight preads, keyed by pid (thread id)
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __type(key, u32);
+  __type(value, pread_start_t);
+  __uint(max_entries, 1 << 14);
+} starts SEC(".maps");
+
+// Group by key
+typedef struct {
+  u64 fd;
+  u64 cpu;
+} group_by_pread_latency_t;
+
+// Aggregations map; the value is the emitted row itself
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __type(key, group_by_pread_latency_t);
+  __type(value, pread_latency_t);
+  __uint(max_entries, 1 << 14);
+  __uint(map_flags, BPF_F_NO_PREALLOC);
+} aggs_pread_latency SEC(".maps");
+
+// Output ringbuf
+#define RB_MAX_ENTRIES (4194280)
+struct {
+  __uint(type, BPF_MAP_TYPE_RINGBUF);
+  __uint(max_entries, RB_MAX_ENTRIES);
+} ring_buf_pread_latency SEC(".maps");
+
+// Load-time parameters, set from userspace: ringbuf size (along with the map's
+// size) and window length in ns
+const volatile u64 RINGBUF_BYTES = RB_MAX_ENTRIES;
+const volatile u64 WINDOW_NS = 1000000000;
+
+// Start of the current window
+u64 window_start = 0;
+
+// Helper callback to count groups
+static __always_inline u64 __count_aggs_pread_latency_callback(struct bpf_map *map,
+                                                               group_by_pread_latency_t *key,
+                                                               pread_latency_t *agg,
+                                                               u64 *count) {
+  *count += 1;
+  return 0;
+}
+
+// Helper callback to copy out and delete groups
+typedef struct {
+  pread_latency_t *buf;
+  u64 buf_sz;
+  u64 count;
+} ctx_t;
+
+static __always_inline u64 __flush_aggs_pread_latency_callback(struct bpf_map *map,
+                                                               group_by_pread_latency_t *key,
+                                                               pread_latency_t *agg,
+                                                               ctx_t *ctx) {
+  if (ctx->count < ctx->buf_sz) {
+    ctx->buf[ctx->count] = *agg;
+    ctx->count += 1;
+  } else {
+    WARN("Number of aggregation results exceeds buf size; dropping group...");
+    count_error(E_TRUNCATED, 1);
+  }
+  bpf_map_delete_elem(map, key);
+  return 0;
+}
+
+// Emits every group of the current window and clears them
+static __always_inline u32 tumble() {
+  u64 count = 0;
+  bpf_for_each_map_elem(&aggs_pread_latency, __count_aggs_pread_latency_callback, &count, 0);
+  if (count == 0) {
+    return 0;
+  }
+  if (count >= (RINGBUF_BYTES / sizeof(pread_latency_t))) {
+    count = (RINGBUF_BYTES / sizeof(pread_latency_t));
+  }
+  pread_latency_t *buf =
+      bpf_ringbuf_reserve(&ring_buf_pread_latency, count * sizeof(pread_latency_t), 0);
+  if (!buf) {
+    ERROR("Failed to allocate from ring buffer");
+    count_error(E_RINGBUF_RESERVE, 1);
+    return 1;
+  }
+  ctx_t ctx = {
+      .buf = buf,
+      .buf_sz = count,
+      .count = 0,
+  };
+  bpf_for_each_map_elem(&aggs_pread_latency, __flush_aggs_pread_latency_callback, &ctx, 0);
+  bpf_ringbuf_submit(buf, 0);
+  return 0;
+}
+
+SEC("tp/syscalls/sys_enter_pread64")
+u32 pread_enter(struct trace_event_raw_sys_enter *ctx) {
+  if (!filter_task()) {
+    return 0;
+  }
+  u32 pid;
+  PID(pid);
+  pread_start_t start = {
+      .start = bpf_ktime_get_ns(),
+      .fd = ctx->args[0],
+  };
+  s64 res = bpf_map_update_elem(&starts, &pid, &start, BPF_ANY);
+  if (res) {
+    ERROR("failed to record pread start: %lld", res);
+  }
+  return 0;
+}
+
+SEC("tp/syscalls/sys_exit_pread64")
+u32 pread_exit(struct trace_event_raw_sys_exit *ctx) {
+  u32 pid;
+  PID(pid);
+  // Missing if the task was filtered out or entered before we attached
+  pread_start_t *start = bpf_map_lookup_elem(&starts, &pid);
+  if (!start) {
+    return 0;
+  }
+  u64 time = bpf_ktime_get_ns();
+  u64 latency = time - start->start;
+  u64 fd = start->fd;
+  bpf_map_delete_elem(&starts, &pid);
+
+  // Check if need to tumble the window
+  if (window_start == 0) {
+    window_start = time;
+  } else if ((time - window_start) > WINDOW_NS) {
+    tumble();
+    window_start = time;
+  }
+
+  u64 cpu;
+  CPU(cpu);
+  group_by_pread_latency_t gb = {fd, cpu};
+  pread_latency_t *agg = bpf_map_lookup_elem(&aggs_pread_latency, &gb);
+  if (!agg) {
+    pread_latency_t init = {.fd = fd, .cpu = cpu};
+    s64 res = bpf_map_update_elem(&aggs_pread_latency, &gb, &init, BPF_NOEXIST);
+    if (res) {
+      ERROR("failed to insert group: %lld", res);
+      count_error(E_MAP_FULL, 1);
+      return 1;
+    }
+    agg = bpf_map_lookup_elem(&aggs_pread_latency, &gb);
+    if (!agg) {
+      return 1;
+    }
+  }
+
+  agg->count += 1;
+  if (ctx->ret < 0) {
+    agg->errors += 1;
+  } else {
+    agg->bytes += ctx->ret;
+  }
+  agg->latency_sum_ns += latency;
+  if (latency > agg->latency_max_ns) {
+    agg->latency_max_ns = latency;
+  }
+  u32 bucket = log2_u64(latency);
+  if (bucket >= LATENCY_BUCKETS) {
+    bucket = LATENCY_BUCKETS - 1;
+  }
+  agg->latency_hist[bucket] += 1;
+  return 0;
+}
+
+// *** LICENSE *** //
+char LICENSE[] SEC("license") = "Dual BSD/GPL";
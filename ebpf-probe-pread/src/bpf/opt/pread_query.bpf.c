@@ -3,12 +3,6 @@
 #include "common.bpf.h"
 #include "pread_query.bpf.h"
 
-// Group by key
-typedef struct {
-  u64 fd;
-  u64 cpu;
-} group_by_pread_query_t;
-
 // Aggregations
 typedef struct {
   // Max
@@ -74,8 +68,7 @@ static __always_inline u64 __get_aggs_pread_query_callback(struct bpf_map *map,
     return 1;
   }
   // Defer computation until here
-  ctx->buf[ctx->count].fd = key->fd;
-  ctx->buf[ctx->count].cpu = key->cpu;
+  set_group_by(&ctx->buf[ctx->count], key);
   ctx->buf[ctx->count].avg_count = agg->val / agg->count;
   ctx->buf[ctx->count].max_count = agg->max;
   ctx->count += 1;
@@ -142,7 +135,7 @@ u32 pread_query(struct trace_event_raw_sys_enter* ctx) {
 	}
 
   // Insert aggregations
-  group_by_pread_query_t gb = {fd, cpu};
+  group_by_pread_query_t gb = make_group_by(fd, cpu);
   agg_t* agg = bpf_map_lookup_elem(&aggs_pread_query, &gb);
   // If non-existent, insert
   if (!agg) {
@@ -5,6 +5,11 @@
 // *** MACRO DEFINITIONS *** //
 
 
+// Must match SIZE_BUCKETS and DISTINCT_BITS / 64 on the Rust side
+#define SIZE_BUCKETS 32
+#define DISTINCT_WORDS 4
+
+
 // *** STRUCT DEFINITIONS *** //
 typedef struct {
 	u64 fd;
@@ -12,9 +17,160 @@ typedef struct {
 	u64 count_;
 	u64 max_count;
 	u64 avg_count;
+	// Start of the window (or pane) the row belongs to, in ktime ns; rows of
+	// one window can be split across samples
+	u64 window_start;
 } pread_query_t;
 
+// Result row with the group by fields beyond fd and cpu and the optional
+// aggregates, which are zero unless selected. Only sent instead of
+// pread_query_t if any of them are selected, so default rows stay small.
+typedef struct {
+	pread_query_t row;
+	u64 tid;
+	u64 pid;
+	u64 cgroup;
+	char comm[16];
+	u64 min_count;
+	u64 sum_count;
+	u64 sum_sq_count;
+	u64 distinct_sketch[DISTINCT_WORDS];
+	u64 count_hist[SIZE_BUCKETS];
+} pread_query_wide_t;
+
+// Group by key. Fields not selected in GROUP_BY stay zero, so they don't split
+// groups.
+typedef struct {
+	u64 fd;
+	u64 cpu;
+	u64 tid;
+	u64 pid;
+	u64 cgroup;
+	char comm[16];
+} group_by_pread_query_t;
+
 
 // *** GLOBAL DEFINITIONS *** //
+// Group by fields; must match GroupBy on the Rust side
+enum GROUP_BY_FIELD {
+	GB_FD = 1 << 0,
+	GB_CPU = 1 << 1,
+	GB_TID = 1 << 2,
+	GB_PID = 1 << 3,
+	GB_CGROUP = 1 << 4,
+	GB_COMM = 1 << 5,
+};
+// Selected group by fields, set from userspace before load
+const volatile u32 GROUP_BY = GB_FD | GB_CPU;
+
+// Builds the group by key of the current event from the selected fields
+static __always_inline group_by_pread_query_t make_group_by(u64 fd, u64 cpu) {
+	group_by_pread_query_t key = {0};
+	u64 pid_tgid = bpf_get_current_pid_tgid();
+	if (GROUP_BY & GB_FD)
+		key.fd = fd;
+	if (GROUP_BY & GB_CPU)
+		key.cpu = cpu;
+	if (GROUP_BY & GB_TID)
+		key.tid = (u32)pid_tgid;
+	if (GROUP_BY & GB_PID)
+		key.pid = pid_tgid >> 32;
+	if (GROUP_BY & GB_CGROUP)
+		key.cgroup = bpf_get_current_cgroup_id();
+	if (GROUP_BY & GB_COMM)
+		bpf_get_current_comm(key.comm, sizeof(key.comm));
+	return key;
+}
+
+// Optional aggregates; must match Aggs on the Rust side
+enum AGG_FIELD {
+	AGG_MIN = 1 << 0,
+	AGG_SUM = 1 << 1,
+	AGG_VARIANCE = 1 << 2,
+	AGG_DISTINCT = 1 << 3,
+	AGG_HIST = 1 << 4,
+};
+// Selected optional aggregates, set from userspace before load
+const volatile u32 AGGS = 0;
+
+// Size of the result rows sent, set from userspace before load:
+// sizeof(pread_query_wide_t) if any group by field beyond fd and cpu or any
+// optional aggregate is selected, else sizeof(pread_query_t)
+const volatile u64 ROW_BYTES = sizeof(pread_query_t);
+#define WIDE_ROWS (ROW_BYTES == sizeof(pread_query_wide_t))
+
+// Row i of a buffer of result rows
+static __always_inline pread_query_t *row_at(void *buf, u64 i) {
+	return buf + i * ROW_BYTES;
+}
+
+// State of the optional aggregates of a group
+typedef struct {
+	u64 count;
+	u64 min;
+	u64 sum;
+	u64 sum_sq;
+	u64 distinct_sketch[DISTINCT_WORDS];
+	u64 hist[SIZE_BUCKETS];
+} ext_agg_t;
+
+// Zeroed state to insert new groups with; too large for the stack
+const ext_agg_t ext_agg_zero = {0};
+
+static __always_inline void ext_agg_add(ext_agg_t *agg, u64 val) {
+	if (agg->count == 0 || val < agg->min)
+		agg->min = val;
+	agg->count += 1;
+	// The sums saturate rather than wrap, as in userspace
+	agg->sum = sat_add_u64(agg->sum, val);
+	if (AGGS & AGG_VARIANCE)
+		agg->sum_sq = sat_add_u64(agg->sum_sq, sat_square_u64(val));
+	if (AGGS & AGG_DISTINCT) {
+		// Top 8 bits of murmur3's 64-bit finalizer
+		u64 h = val;
+		h ^= h >> 33;
+		h *= 0xff51afd7ed558ccdULL;
+		h ^= h >> 33;
+		h *= 0xc4ceb9fe1a85ec53ULL;
+		h ^= h >> 33;
+		u32 bit = h >> 56;
+		agg->distinct_sketch[(bit / 64) & (DISTINCT_WORDS - 1)] |= 1ULL << (bit % 64);
+	}
+	if (AGGS & AGG_HIST) {
+		u32 bucket = log2_u64(val);
+		if (bucket >= SIZE_BUCKETS)
+			bucket = SIZE_BUCKETS - 1;
+		agg->hist[bucket] += 1;
+	}
+}
 
+// Copies the selected optional aggregates into a wide result row, zeroing the
+// rest (ring buffer space is not zeroed on reserve)
+static __always_inline void set_ext_agg(pread_query_t *row, const ext_agg_t *agg) {
+	if (!WIDE_ROWS)
+		return;
+	pread_query_wide_t *wide = (pread_query_wide_t *)row;
+	wide->min_count = (AGGS & AGG_MIN) ? agg->min : 0;
+	wide->sum_count = (AGGS & (AGG_SUM | AGG_VARIANCE)) ? agg->sum : 0;
+	wide->sum_sq_count = (AGGS & AGG_VARIANCE) ? agg->sum_sq : 0;
+	// Only filled in when selected
+	__builtin_memcpy(wide->distinct_sketch, agg->distinct_sketch, sizeof(wide->distinct_sketch));
+	__builtin_memcpy(wide->count_hist, agg->hist, sizeof(wide->count_hist));
+}
 
+// Copies the group by key into a result row. Map keys end after the last
+// selected field, so the fields past fd and cpu are only read if selected.
+static __always_inline void set_group_by(pread_query_t *row, group_by_pread_query_t *key) {
+	row->fd = key->fd;
+	row->cpu = key->cpu;
+	if (!WIDE_ROWS)
+		return;
+	pread_query_wide_t *wide = (pread_query_wide_t *)row;
+	wide->tid = (GROUP_BY & GB_TID) ? key->tid : 0;
+	wide->pid = (GROUP_BY & GB_PID) ? key->pid : 0;
+	wide->cgroup = (GROUP_BY & GB_CGROUP) ? key->cgroup : 0;
+	if (GROUP_BY & GB_COMM)
+		__builtin_memcpy(wide->comm, key->comm, sizeof(wide->comm));
+	else
+		__builtin_memset(wide->comm, 0, sizeof(wide->comm));
+}
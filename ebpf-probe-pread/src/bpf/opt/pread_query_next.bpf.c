@@ -3,30 +3,39 @@
 #include "common.bpf.h"
 #include "pread_query.bpf.h"
 
-// Group by key
-typedef struct {
-  u64 fd;
-} group_by_pread_query_t;
-
 // Aggregations
 typedef struct {
   // Max
   u64 max;
-  // Summed value for average
+  // Summed value for average; saturates rather than wraps, as in userspace
   u64 val;
   // Counts
   u64 count;
 } agg_t;
 
-// Aggregations map
+// Keeps the row types in BTF for the layout checks in build.rs
+pread_query_t _pread_query_t = {0};
+pread_query_wide_t _pread_query_wide_t = {0};
+
+// Aggregations map, keyed on group_by_pread_query_t up to its last selected
+// field; userspace shrinks the key size before load
 struct {
   __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
-  __type(key, group_by_pread_query_t);
+  __uint(key_size, sizeof(group_by_pread_query_t));
   __type(value, agg_t);
   __uint(max_entries, 1<<10);
   __uint(map_flags, BPF_F_NO_PREALLOC);
 } aggs_pread_query SEC(".maps");
 
+// Optional aggregates; only updated if any are selected in AGGS
+struct {
+  __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+  __uint(key_size, sizeof(group_by_pread_query_t));
+  __type(value, ext_agg_t);
+  __uint(max_entries, 1<<10);
+  __uint(map_flags, BPF_F_NO_PREALLOC);
+} ext_aggs_pread_query SEC(".maps");
+
 // Output ringbuf
 #define RB_MAX_ENTRIES (4194280)
 struct {
@@ -34,10 +43,36 @@ struct {
 	__uint(max_entries, RB_MAX_ENTRIES);
 } ring_buf_pread_query SEC(".maps");
 
-// A second in NS
-#define SEC_NS (1000000000)
-// Record last seen value timestamp
-u64 last_time = 0;
+// Load-time parameters, set from userspace: ringbuf size (along with the map's
+// size) and window length in ns
+const volatile u64 RINGBUF_BYTES = RB_MAX_ENTRIES;
+const volatile u64 WINDOW_NS = 1000000000;
+
+// Rows of a window sent through perf_buf are staged here, in the entry of the
+// emitting CPU; sized to the number of CPUs from userspace when used
+#define PERF_SAMPLES 16
+#define PERF_MAX_ROWS (PERF_SAMPLES * (PERF_SAMPLE_MAX / ROW_BYTES))
+typedef struct {
+  u8 rows[PERF_SAMPLES * PERF_SAMPLE_MAX];
+} perf_rows_pread_query_t;
+struct {
+  __uint(type, BPF_MAP_TYPE_ARRAY);
+  __type(key, u32);
+  __type(value, perf_rows_pread_query_t);
+  __uint(max_entries, 1);
+} perf_rows_pread_query SEC(".maps");
+
+// Start of the window each CPU's aggregates belong to. The aggregates are
+// per-CPU, so each CPU closes its own windows; they all lie on the grid of
+// WINDOW_NS from the first event, window_epoch.
+struct {
+  __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+  __type(key, u32);
+  __type(value, u64);
+  __uint(max_entries, 1);
+} window_pread_query SEC(".maps");
+
+u64 window_epoch = 0;
 // u64 gb_count = 0;
 
 
@@ -52,11 +87,22 @@ static __always_inline u64 __clear_aggs_pread_query_callback(struct bpf_map *map
   return 0;
 }
 
+// Helper callback to clear the optional aggregations map
+static __always_inline u64 __clear_ext_aggs_pread_query_callback(struct bpf_map *map,
+                                                                 group_by_pread_query_t *key,
+                                                                 ext_agg_t *agg,
+                                                                 void *ctx) {
+  __builtin_memset(agg, 0, sizeof(*agg));
+  return 0;
+}
+
 // Helper callback to get aggregations
 typedef struct {
-  pread_query_t *buf;
+  // Rows of ROW_BYTES each
+  void *buf;
   u64 buf_sz;
   u64 count;
+  u64 window_start;
 } ctx_t;
 
 static __always_inline u64 __get_aggs_pread_query_callback(struct bpf_map *map, group_by_pread_query_t *key, agg_t *agg, ctx_t *ctx) {
@@ -73,11 +119,17 @@ static __always_inline u64 __get_aggs_pread_query_callback(struct bpf_map *map,
     WARN("Number of aggregation results exceeds buf size; stopping...");
     return 1;
   }
-  // Defer computation until here
-  ctx->buf[ctx->count].fd = key->fd;
-  ctx->buf[ctx->count].cpu = bpf_get_smp_processor_id();
-  ctx->buf[ctx->count].avg_count = agg->val / agg->count;
-  ctx->buf[ctx->count].max_count = agg->max;
+  // Defer computation until here. The map is per-CPU, so rows are always split
+  // by CPU.
+  pread_query_t *row = row_at(ctx->buf, ctx->count);
+  set_group_by(row, key);
+  row->cpu = bpf_get_smp_processor_id();
+  row->window_start = ctx->window_start;
+  row->count_ = agg->count;
+  row->avg_count = agg->val / agg->count;
+  ext_agg_t *ext = AGGS ? bpf_map_lookup_elem(&ext_aggs_pread_query, key) : NULL;
+  set_ext_agg(row, ext ? ext : &ext_agg_zero);
+  row->max_count = agg->max;
   ctx->count += 1;
   return 0;
 }
@@ -93,55 +145,108 @@ __count_aggs_pread_query_callback(struct bpf_map *map, group_by_pread_query_t *k
   return 0;
 }
 
-SEC("tp/syscalls/sys_enter_pread64")
-u32 pread_query(struct trace_event_raw_sys_enter* ctx) {
-  u64 time = bpf_ktime_get_ns();
-  u64 fd = ctx->args[0];
-  u64 count = ctx->args[2];
+// Start of the window holding `time`
+static __always_inline u64 window_start_at(u64 time) {
+  if (time < window_epoch) {
+    return window_epoch;
+  }
+  return window_epoch + (time - window_epoch) / WINDOW_NS * WINDOW_NS;
+}
 
-  // Check if need to reset second timer
-  bool reset = false;
-  if (last_time == 0) {
-    last_time = time;
-  } else if ((time - last_time) > SEC_NS) {
-    reset = true;
-  }
-
-  if (reset) {
-    // Get # of unique values
-    u64 count = 0;
-    // Need to use for each map elem since BPF for some reason doesn't allow non-constants
-    // (i.e. if I tried using `gb_count`), but does allow something computed like this...
-    bpf_for_each_map_elem(&aggs_pread_query, __count_aggs_pread_query_callback, &count, 0);
-    if (count > 0) {
-      if (count >= (RB_MAX_ENTRIES / sizeof(pread_query_t))) {
-        count = (RB_MAX_ENTRIES / sizeof(pread_query_t));
+// Start of the window holding `time`, after this CPU's window at `start`;
+// the empty windows in between are counted as skipped
+static __always_inline u64 window_next_start(u64 start, u64 time) {
+  u64 next = window_start_at(time);
+  if (next > start + WINDOW_NS) {
+    count_error(E_WINDOW_SKIP, (next - start) / WINDOW_NS - 1);
+  }
+  return next;
+}
+
+// Emits this CPU's groups of the window that started at `start` and clears
+// them. Each CPU emits its own rows of a window; userspace puts them together
+// by their window_start.
+static __always_inline u32 flush_cpu(void* ctx, u64 start) {
+  // Get # of unique values
+  u64 count = 0;
+  // Need to use for each map elem since BPF for some reason doesn't allow non-constants
+  // (i.e. if I tried using `gb_count`), but does allow something computed like this...
+  bpf_for_each_map_elem(&aggs_pread_query, __count_aggs_pread_query_callback, &count, 0);
+  if (count > 0) {
+    u64 max_count = (TRANSPORT == T_PERFBUF) ? PERF_MAX_ROWS
+                                             : RINGBUF_BYTES / ROW_BYTES;
+    if (count >= max_count) {
+      count_error(E_TRUNCATED, count - max_count);
+      count = max_count;
+    }
+    void* buf;
+    if (TRANSPORT == T_PERFBUF) {
+      u32 cpu = bpf_get_smp_processor_id();
+      buf = bpf_map_lookup_elem(&perf_rows_pread_query, &cpu);
+      if (!buf) {
+        ERROR("No perf staging rows for CPU %u", cpu);
+        return 1;
       }
-      pread_query_t* buf =
-          bpf_ringbuf_reserve(&ring_buf_pread_query, count * sizeof(pread_query_t), 0);
+    } else {
+      buf = bpf_ringbuf_reserve(&ring_buf_pread_query, count * ROW_BYTES, 0);
       if (!buf) {
         ERROR("Failed to allocate from ring buffer");
+        count_error(E_RINGBUF_RESERVE, 1);
         return 1;
       }
-      // Create result values
-      ctx_t ctx = {
-        .buf = buf,
-        .buf_sz = count,
-        .count = 0,
-      };
-      bpf_for_each_map_elem(&aggs_pread_query, __get_aggs_pread_query_callback, &ctx, 0);
+    }
+    // Create result values
+    ctx_t get_ctx = {
+      .buf = buf,
+      .buf_sz = count,
+      .count = 0,
+      .window_start = start,
+    };
+    bpf_for_each_map_elem(&aggs_pread_query, __get_aggs_pread_query_callback, &get_ctx, 0);
+    if (TRANSPORT == T_PERFBUF) {
+      perf_output_records(ctx, buf, ROW_BYTES, get_ctx.count, PERF_SAMPLES);
+    } else {
       bpf_ringbuf_submit(buf, 0);
     }
+  }
+
+  // Clear aggs map
+  bpf_for_each_map_elem(&aggs_pread_query, __clear_aggs_pread_query_callback, 0, 0);
+  if (AGGS) {
+    bpf_for_each_map_elem(&ext_aggs_pread_query, __clear_ext_aggs_pread_query_callback, 0, 0);
+  }
+  return 0;
+}
+
+SEC("tp/syscalls/sys_enter_pread64")
+u32 pread_query(struct trace_event_raw_sys_enter* ctx) {
+  if (!filter_task()) {
+    return 0;
+  }
+  u64 time = bpf_ktime_get_ns();
+  u64 fd = ctx->args[0];
+  u64 count = ctx->args[2];
 
-    // Clear aggs map
-    bpf_for_each_map_elem(&aggs_pread_query, __clear_aggs_pread_query_callback, 0, 0);
-    // Update counters
-    last_time = time;
+  // Check if this CPU's window is over
+  u64 *start = bpf_map_lookup_elem(&window_pread_query, &zero);
+  if (!start) {
+    return 1;
+  }
+  if (window_epoch == 0) {
+    __sync_val_compare_and_swap(&window_epoch, 0, time);
+  }
+  if (*start == 0) {
+    *start = window_start_at(time);
+  } else if (time >= *start + WINDOW_NS) {
+    if (flush_cpu(ctx, *start)) {
+      return 1;
+    }
+    *start = window_next_start(*start, time);
     // gb_count = 0;
-	}
+  }
 
-  // Insert aggregations
-  group_by_pread_query_t gb = {fd};
+  // Insert aggregations; the per-CPU map already splits by CPU
+  group_by_pread_query_t gb = make_group_by(fd, 0);
   agg_t* agg = bpf_map_lookup_elem(&aggs_pread_query, &gb);
   // If non-existent, insert
   if (!agg) {
@@ -151,8 +256,9 @@ u32 pread_query(struct trace_event_raw_sys_enter* ctx) {
         .count = 1,
     };
     s64 res = bpf_map_update_elem(&aggs_pread_query, &gb, &agg, BPF_NOEXIST);
-    if (!res) {
+    if (res) {
       ERROR("failed to update map elem: %lld", res);
+      count_error(E_MAP_FULL, 1);
     }
     // Update total count
     // gb_count += 1;
@@ -162,13 +268,46 @@ u32 pread_query(struct trace_event_raw_sys_enter* ctx) {
 
     // Update aggregate values
     agg->max = (agg->max < count) ? count : agg->max;
-    agg->val += count;
+    agg->val = sat_add_u64(agg->val, count);
     agg->count += 1;
   }
 
+  if (AGGS) {
+    ext_agg_t *ext = bpf_map_lookup_elem(&ext_aggs_pread_query, &gb);
+    if (!ext) {
+      // Too large to build on the stack; insert zeroed and update in place
+      if (bpf_map_update_elem(&ext_aggs_pread_query, &gb, &ext_agg_zero, BPF_NOEXIST)) {
+        count_error(E_MAP_FULL, 1);
+      }
+      ext = bpf_map_lookup_elem(&ext_aggs_pread_query, &gb);
+    }
+    if (ext) {
+      ext_agg_add(ext, count);
+    }
+  }
+
 	return 0;
 }
 
+// Closes this CPU's window if it is due, or whenever ctx[0] is set. Not
+// attached; userspace runs it on every CPU through BPF_PROG_TEST_RUN so
+// windows close on schedule through quiet periods, and with ctx[0] set on
+// shutdown.
+SEC("raw_tp")
+u32 flush_pread_query(u64* ctx) {
+  u64 *start = bpf_map_lookup_elem(&window_pread_query, &zero);
+  if (!start || *start == 0) {
+    return 0;
+  }
+  u64 time = bpf_ktime_get_ns();
+  if (!ctx[0] && time < *start + WINDOW_NS) {
+    return 0;
+  }
+  u64 closed = *start;
+  *start = window_next_start(*start, time);
+  return flush_cpu(ctx, closed);
+}
+
 
 // *** LICENSE *** //
 char LICENSE[] SEC("license") = "Dual BSD/GPL";
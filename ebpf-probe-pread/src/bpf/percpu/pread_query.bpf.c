@@ -0,0 +1,83 @@
+// *** SOURCE FOR pread_query *** //
+//
+// The ebql pread query without state shared between CPUs. Each pread is added
+// to its group in a per-CPU map keyed by window and group, where the window is
+// the pread's index on a grid of WINDOW_NS from the first event, so nothing
+// decides when a window closes in the kernel. Userspace drains the map,
+// merging the groups across CPUs, and reports the windows that are over.
+
+#include "common.bpf.h"
+#include "../ebql/pread_query.bpf.h"
+
+// Aggregations map key
+typedef struct {
+  u64 window;
+  group_by_pread_query_t gb;
+} window_group_t;
+
+// Aggregations of a group on one CPU; count, min and sum are always kept in
+// ext
+typedef struct {
+  u64 max;
+  ext_agg_t ext;
+} percpu_agg_t;
+
+// Keeps percpu_agg_t in BTF for the layout check in build.rs
+percpu_agg_t _percpu_agg_t = {0};
+
+// Zeroed state to insert new groups with; too large for the stack
+const percpu_agg_t percpu_agg_zero = {0};
+
+// Aggregations map
+struct {
+  __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+  __type(key, window_group_t);
+  __type(value, percpu_agg_t);
+  __uint(max_entries, 1 << 14);
+  __uint(map_flags, BPF_F_NO_PREALLOC);
+} aggs_pread_query SEC(".maps");
+
+// Window length in ns; set from userspace before load
+const volatile u64 WINDOW_NS = 1000000000;
+
+// Time of the first event, where the window grid starts. Only ever set once.
+u64 window_epoch = 0;
+
+SEC("tp/syscalls/sys_enter_pread64")
+u32 pread_query(struct trace_event_raw_sys_enter* ctx) {
+  if (!filter_task()) {
+    return 0;
+  }
+  u64 time = bpf_ktime_get_ns();
+  u64 fd = ctx->args[0];
+  u64 count = ctx->args[2];
+  u64 cpu;
+  CPU(cpu);
+
+  if (window_epoch == 0) {
+    __sync_val_compare_and_swap(&window_epoch, 0, time);
+  }
+  u64 epoch = window_epoch;
+  window_group_t key = {
+      .window = (time < epoch) ? 0 : (time - epoch) / WINDOW_NS,
+      .gb = make_group_by(fd, cpu),
+  };
+
+  percpu_agg_t* agg = bpf_map_lookup_elem(&aggs_pread_query, &key);
+  if (!agg) {
+    bpf_map_update_elem(&aggs_pread_query, &key, &percpu_agg_zero, BPF_NOEXIST);
+    agg = bpf_map_lookup_elem(&aggs_pread_query, &key);
+    if (!agg) {
+      ERROR("failed to insert group");
+      count_error(E_MAP_FULL, 1);
+      return 1;
+    }
+  }
+  agg->max = (agg->max < count) ? count : agg->max;
+  ext_agg_add(&agg->ext, count);
+  return 0;
+}
+
+
+// *** LICENSE *** //
+char LICENSE[] SEC("license") = "Dual BSD/GPL";
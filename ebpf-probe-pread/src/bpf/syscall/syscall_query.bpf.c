@@ -0,0 +1,224 @@
+// *** SOURCE FOR syscall_query *** //
+//
+// The pread query generalized to other I/O syscalls: count, max and average of
+// the byte count per (syscall, fd, cpu), over tumbling windows like the
+// pread_query probes. Each syscall has its own program; userspace disables the
+// unselected ones with autoload before load.
+
+#include "common.bpf.h"
+
+// Must match the Syscall enum on the Rust side
+enum SYSCALL {
+  SYS_READ = 0,
+  SYS_PREAD64,
+  SYS_WRITE,
+  SYS_PWRITE64,
+  SYS_FSYNC,
+  SYS_PREADV,
+  SYS_IO_URING,
+};
+
+// iovecs of a preadv summed for its byte count; the rest are ignored
+#define MAX_IOVECS 16
+
+// Emitted struct
+typedef struct {
+  u64 syscall;
+  u64 fd;
+  u64 cpu;
+  u64 count_;
+  u64 max_count;
+  u64 avg_count;
+} syscall_query_t;
+
+// Keeps syscall_query_t in BTF for the layout check in build.rs
+syscall_query_t _syscall_query_t = {0};
+
+// Group by key
+typedef struct {
+  u64 syscall;
+  u64 fd;
+  u64 cpu;
+} group_by_syscall_query_t;
+
+// Aggregations
+typedef struct {
+  // Max
+  u64 max;
+  // Summed value for average
+  u64 val;
+  // Counts
+  u64 count;
+} agg_t;
+
+// Aggregations map
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __type(key, group_by_syscall_query_t);
+  __type(value, agg_t);
+  __uint(max_entries, 1 << 14);
+  __uint(map_flags, BPF_F_NO_PREALLOC);
+} aggs_syscall_query SEC(".maps");
+
+// Output ringbuf
+#define RB_MAX_ENTRIES (4194280)
+struct {
+  __uint(type, BPF_MAP_TYPE_RINGBUF);
+  __uint(max_entries, RB_MAX_ENTRIES);
+} ring_buf_syscall_query SEC(".maps");
+
+// Load-time parameters, set from userspace: ringbuf size (along with the map's
+// size) and window length in ns
+const volatile u64 RINGBUF_BYTES = RB_MAX_ENTRIES;
+const volatile u64 WINDOW_NS = 1000000000;
+
+// Start of the current window
+u64 window_start = 0;
+
+// Helper callback to count groups
+static __always_inline u64 __count_aggs_syscall_query_callback(struct bpf_map *map,
+                                                               group_by_syscall_query_t *key,
+                                                               agg_t *agg, u64 *count) {
+  *count += 1;
+  return 0;
+}
+
+// Helper callback to copy out and delete groups
+typedef struct {
+  syscall_query_t *buf;
+  u64 buf_sz;
+  u64 count;
+} ctx_t;
+
+static __always_inline u64 __flush_aggs_syscall_query_callback(struct bpf_map *map,
+                                                               group_by_syscall_query_t *key,
+                                                               agg_t *agg, ctx_t *ctx) {
+  if (ctx->count < ctx->buf_sz) {
+    syscall_query_t *row = &ctx->buf[ctx->count];
+    row->syscall = key->syscall;
+    row->fd = key->fd;
+    row->cpu = key->cpu;
+    row->count_ = agg->count;
+    row->max_count = agg->max;
+    row->avg_count = agg->val / agg->count;
+    ctx->count += 1;
+  } else {
+    WARN("Number of aggregation results exceeds buf size; dropping group...");
+    count_error(E_TRUNCATED, 1);
+  }
+  bpf_map_delete_elem(map, key);
+  return 0;
+}
+
+// Emits every group of the current window and clears them
+static __always_inline u32 tumble() {
+  u64 count = 0;
+  bpf_for_each_map_elem(&aggs_syscall_query, __count_aggs_syscall_query_callback, &count, 0);
+  if (count == 0) {
+    return 0;
+  }
+  if (count >= (RINGBUF_BYTES / sizeof(syscall_query_t))) {
+    count = (RINGBUF_BYTES / sizeof(syscall_query_t));
+  }
+  syscall_query_t *buf =
+      bpf_ringbuf_reserve(&ring_buf_syscall_query, count * sizeof(syscall_query_t), 0);
+  if (!buf) {
+    ERROR("Failed to allocate from ring buffer");
+    count_error(E_RINGBUF_RESERVE, 1);
+    return 1;
+  }
+  ctx_t ctx = {
+      .buf = buf,
+      .buf_sz = count,
+      .count = 0,
+  };
+  bpf_for_each_map_elem(&aggs_syscall_query, __flush_aggs_syscall_query_callback, &ctx, 0);
+  bpf_ringbuf_submit(buf, 0);
+  return 0;
+}
+
+// Adds one call to its group, tumbling the window first if it is over
+static __always_inline u32 aggregate(u64 syscall, u64 fd, u64 count) {
+  if (!filter_task()) {
+    return 0;
+  }
+  u64 time = bpf_ktime_get_ns();
+  if (window_start == 0) {
+    window_start = time;
+  } else if ((time - window_start) > WINDOW_NS) {
+    tumble();
+    window_start = time;
+  }
+
+  u64 cpu;
+  CPU(cpu);
+  group_by_syscall_query_t gb = {syscall, fd, cpu};
+  agg_t *agg = bpf_map_lookup_elem(&aggs_syscall_query, &gb);
+  if (!agg) {
+    agg_t init = {
+        .max = count,
+        .val = count,
+        .count = 1,
+    };
+    s64 res = bpf_map_update_elem(&aggs_syscall_query, &gb, &init, BPF_NOEXIST);
+    if (res) {
+      ERROR("failed to insert group: %lld", res);
+      count_error(E_MAP_FULL, 1);
+      return 1;
+    }
+    return 0;
+  }
+  agg->max = (agg->max < count) ? count : agg->max;
+  agg->val += count;
+  agg->count += 1;
+  return 0;
+}
+
+SEC("tp/syscalls/sys_enter_read")
+u32 sys_read(struct trace_event_raw_sys_enter *ctx) {
+  return aggregate(SYS_READ, ctx->args[0], ctx->args[2]);
+}
+
+SEC("tp/syscalls/sys_enter_pread64")
+u32 sys_pread64(struct trace_event_raw_sys_enter *ctx) {
+  return aggregate(SYS_PREAD64, ctx->args[0], ctx->args[2]);
+}
+
+SEC("tp/syscalls/sys_enter_write")
+u32 sys_write(struct trace_event_raw_sys_enter *ctx) {
+  return aggregate(SYS_WRITE, ctx->args[0], ctx->args[2]);
+}
+
+SEC("tp/syscalls/sys_enter_pwrite64")
+u32 sys_pwrite64(struct trace_event_raw_sys_enter *ctx) {
+  return aggregate(SYS_PWRITE64, ctx->args[0], ctx->args[2]);
+}
+
+SEC("tp/syscalls/sys_enter_fsync")
+u32 sys_fsync(struct trace_event_raw_sys_enter *ctx) {
+  return aggregate(SYS_FSYNC, ctx->args[0], 0);
+}
+
+SEC("tp/syscalls/sys_enter_preadv")
+u32 sys_preadv(struct trace_event_raw_sys_enter *ctx) {
+  const struct iovec *vec = (const struct iovec *)ctx->args[1];
+  u64 vlen = ctx->args[2];
+  u64 count = 0;
+  for (u32 i = 0; i < MAX_IOVECS && i < vlen; i++) {
+    struct iovec iov;
+    if (bpf_probe_read_user(&iov, sizeof(iov), &vec[i])) {
+      break;
+    }
+    count += iov.iov_len;
+  }
+  return aggregate(SYS_PREADV, ctx->args[0], count);
+}
+
+SEC("tp/io_uring/io_uring_submit_sqe")
+u32 sys_io_uring(struct trace_event_raw_io_uring_submit_sqe *ctx) {
+  // No fd in the tracepoint; group by opcode
+  return aggregate(SYS_IO_URING, ctx->opcode, 0);
+}
+
+// *** LICENSE *** //
+char LICENSE[] SEC("license") = "Dual BSD/GPL";
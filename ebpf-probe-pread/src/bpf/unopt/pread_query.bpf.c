@@ -8,6 +8,9 @@ typedef struct {
   u64 count;
 } raw_pread_t;
 
+// Keeps raw_pread_t in BTF for the layout check in build.rs
+raw_pread_t _raw_pread_t = {0};
+
 // Output ringbuf
 #define RB_MAX_ENTRIES (1024 * sizeof(raw_pread_t))
 struct {
@@ -17,10 +20,24 @@ struct {
 
 SEC("tp/syscalls/sys_enter_pread64")
 u32 pread_query(struct trace_event_raw_sys_enter* ctx) {
+  if (!filter_task()) {
+    return 0;
+  }
+  if (TRANSPORT == T_PERFBUF) {
+    raw_pread_t q = {
+        .time = bpf_ktime_get_ns(),
+        .fd = ctx->args[0],
+        .cpu = bpf_get_smp_processor_id(),
+        .count = ctx->args[2],
+    };
+    bpf_perf_event_output(ctx, &perf_buf, BPF_F_CURRENT_CPU, &q, sizeof(q));
+    return 0;
+  }
   raw_pread_t* q =
       bpf_ringbuf_reserve(&ring_buf_pread_query, sizeof(raw_pread_t), 0);
       if (!q) {
         ERROR("failed to allocate space in ring buffer");
+        count_error(E_RINGBUF_RESERVE, 1);
         return 1;
       }
   q->time = bpf_ktime_get_ns();
@@ -1,43 +1,40 @@
-mod pread_query {
-    pub mod ebql {
-        include!(concat!(env!("OUT_DIR"), "/ebql_pread_query.skel.rs"));
-    }
-
-    pub mod opt {
-        include!(concat!(env!("OUT_DIR"), "/opt_pread_query.skel.rs"));
-    }
-
-    pub mod unopt {
-        include!(concat!(env!("OUT_DIR"), "/unopt_pread_query.skel.rs"));
-    }
-}
+mod probes;
 
 use std::{
-    collections::{btree_map::Entry, BTreeMap},
-    fs,
-    io::Write,
+    mem,
+    path::PathBuf,
+    process,
     sync::{
         atomic::{AtomicBool, Ordering::SeqCst},
         Arc,
     },
-    thread,
     time::{Duration, Instant},
 };
 
-use clap::Parser;
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    error::ErrorKind,
+    CommandFactory, FromArgMatches, Parser,
+};
 use common::{
+    aggs::Aggs,
+    bpf_log::{self, BpfLogLevel},
     bpf_prog, bpf_stats,
-    bpf_structs::{PreadQueryRecord, RawPreadRecord},
-    prog_stats::ProgStats,
-};
-use crossbeam::channel;
-use libbpf_rs::{
-    skel::{OpenSkel, SkelBuilder},
-    RingBufferBuilder,
+    consume::Consume,
+    files,
+    group_by::GroupBy,
+    probe::{ProbeRegistry, RunOptions, RunOutput, Supports, TaskFilter},
+    report::{self, RunReport},
+    syscall::Syscall,
+    transport::Transport,
+    window::WindowSpec,
 };
-use pread_query::*;
+use serde::Serialize;
 
-// static DONE: AtomicBool = AtomicBool::new(false);
+/// Mismatching groups printed after validating; all are counted.
+const MAX_MISMATCHES_SHOWN: usize = 20;
+/// Files printed after probing, most-read first; all go into the report.
+const MAX_FILES_SHOWN: usize = 10;
 
 fn init_signal(done: Arc<AtomicBool>) {
     ctrlc::set_handler(move || {
@@ -46,196 +43,450 @@ fn init_signal(done: Arc<AtomicBool>) {
     .expect("Error setting Ctrl-C handler");
 }
 
-#[derive(Parser, Debug, Clone)]
+#[derive(Parser, Debug, Clone, Serialize)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    #[arg(short, long)]
-    probe_type: String,
+    /// Probe variant to run; see --list-probes
+    #[arg(
+        short,
+        long,
+        ignore_case = true,
+        required_unless_present = "list_probes"
+    )]
+    probe_type: Option<String>,
+
+    /// List the registered probe variants and exit
+    #[arg(long)]
+    list_probes: bool,
 
+    /// Appends a run report to this path (JSON lines) and to `<path>.csv`
     #[arg(short, long, default_value_t=String::from(""))]
     stats_path: String,
+
+    /// Periodically samples the probe's BPF program stats into this CSV file
+    #[arg(long)]
+    bpf_samples_path: Option<PathBuf>,
+
+    /// Interval between BPF stats samples
+    #[arg(long, default_value_t = 1000)]
+    bpf_sample_interval_ms: u64,
+
+    /// Only counts preads of this thread (kernel pid)
+    #[arg(long)]
+    pid: Option<u32>,
+
+    /// Only counts preads of this process (kernel tgid, the pid `ps` shows)
+    #[arg(long)]
+    tgid: Option<u32>,
+
+    /// Only counts preads of tasks in this cgroup, given as a cgroup v2 id or
+    /// path (absolute or relative to /sys/fs/cgroup)
+    #[arg(long)]
+    cgroup: Option<String>,
+
+    /// Window length of the query; defaults to one second
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    window_ms: Option<u64>,
+
+    /// Makes time windows slide by this step instead of tumbling; the window
+    /// length must be a multiple of it (ebql and unopt only)
+    #[arg(long, requires = "window_ms")]
+    window_step_ms: Option<u64>,
+
+    /// Counts windows in preads instead of time (ebql and unopt only)
+    #[arg(long, conflicts_with_all = ["window_ms", "session_gap_ms"])]
+    window_count: Option<u64>,
+
+    /// Makes count windows slide by this many preads instead of tumbling
+    #[arg(long, requires = "window_count")]
+    window_count_step: Option<u64>,
+
+    /// Uses session windows that close after this long without preads (ebql
+    /// and unopt only)
+    #[arg(long, conflicts_with = "window_ms")]
+    session_gap_ms: Option<u64>,
+
+    /// Maximum number of groups the in-kernel queries track per window
+    #[arg(long)]
+    max_groups: Option<u32>,
+
+    /// Size of the probe's output ring buffer, rounded up to a power-of-two
+    /// number of pages
+    #[arg(long)]
+    ringbuf_bytes: Option<u32>,
+
+    /// How the BPF programs hand records to userspace: ringbuf, perfbuf
+    /// (ebql, opt and unopt only) or mappoll, where userspace drains the
+    /// aggregation maps (ebql and percpu, which supports nothing else). Only
+    /// ringbuf supports sliding, count and session windows
+    #[arg(
+        long,
+        default_value_t = Transport::Ringbuf,
+        value_parser = PossibleValuesParser::new(Transport::NAMES)
+            .map(|s| s.parse::<Transport>().unwrap()),
+    )]
+    transport: Transport,
+
+    /// Where records are consumed: threaded, on the main thread while a
+    /// poller thread reads the output buffer, or inline, from the output
+    /// buffer callback
+    #[arg(
+        long,
+        default_value_t = Consume::Threaded,
+        value_parser = PossibleValuesParser::new(Consume::NAMES)
+            .map(|s| s.parse::<Consume>().unwrap()),
+    )]
+    consume: Consume,
+
+    /// Threshold of the BPF programs' log statements
+    #[arg(
+        long,
+        default_value_t = BpfLogLevel::Off,
+        value_parser = PossibleValuesParser::new(BpfLogLevel::NAMES)
+            .map(|s| s.parse::<BpfLogLevel>().unwrap()),
+    )]
+    bpf_log_level: BpfLogLevel,
+
+    /// Fields the query groups by, comma-separated: fd, cpu, tid, pid, cgroup
+    /// and comm (ebql, opt and percpu only; opt always splits by cpu)
+    #[arg(long, default_value_t = GroupBy::default())]
+    group_by: GroupBy,
+
+    /// Optional aggregates of the pread size, comma-separated: min, sum,
+    /// variance, distinct and hist (ebql, opt, percpu and unopt only)
+    #[arg(long, default_value_t = Aggs::default())]
+    aggs: Aggs,
+
+    /// Syscalls the syscall probe aggregates, comma-separated
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "pread64",
+        value_parser = PossibleValuesParser::new(Syscall::NAMES)
+            .map(|s| s.parse::<Syscall>().unwrap()),
+    )]
+    syscalls: Vec<Syscall>,
+
+    /// Tails the kernel trace pipe and logs BPF log statements alongside the
+    /// probe's own logs
+    #[arg(long)]
+    bpf_trace_pipe: bool,
+
+    /// Runs the unopt probe alongside the selected one and checks the
+    /// selected probe's windows against a userspace reference (ebql and opt
+    /// only)
+    #[arg(long)]
+    validate: bool,
+
+    /// Per-group count difference tolerated when validating, for preads at
+    /// window boundaries
+    #[arg(long, default_value_t = 2)]
+    validate_slack: u64,
+
+    /// Identifier recorded in the run report; defaults to start time and pid
+    #[arg(long, default_value_t = report::default_run_id())]
+    run_id: String,
 }
 
-fn main() {
-    let args = Args::parse();
-    if args.probe_type.is_empty() {
-        panic!("Must provide probe type!");
+/// Parses arguments, restricting `--probe-type` to the registered probes.
+fn parse_args(registry: &ProbeRegistry) -> Args {
+    let matches = Args::command()
+        .mut_arg("probe_type", |arg| {
+            arg.value_parser(PossibleValuesParser::new(registry.names()))
+        })
+        .get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(probe_type) = &args.probe_type {
+        check_capabilities(registry, &probe_type.to_lowercase(), &args);
     }
-    let done = Arc::new(AtomicBool::new(false));
-    init_signal(done.clone());
-    bpf_prog::bump_memlock_rlimit().unwrap();
-    bpf_prog::init_log(log::LevelFilter::Trace);
-    bpf_stats::enable_bpf_stats().unwrap();
-
-    // Create channel to receive records
-    let (tx, rx) = channel::bounded(1024);
-    let process_records = bpf_prog::create_event_handler::<PreadQueryRecord>(tx, done.clone());
-    let (tx1, rx1) = channel::bounded(1024);
-    let process_records_raw = bpf_prog::create_event_handler::<RawPreadRecord>(tx1, done.clone());
-
-    let probe_type = args.probe_type.to_lowercase();
-    // Depending on probe type, build different programs
-    let (rb, _link) = match probe_type.as_str() {
-        "ebql" => {
-            log::info!("starting ebql probe");
-            let mut skel = ebql::PreadQuerySkelBuilder::default()
-                .open()
-                .unwrap()
-                .load()
-                .unwrap();
-            // Create rb handler
-            let maps = skel.maps();
-            let mut builder = RingBufferBuilder::new();
-            builder
-                .add(&maps.ring_buf_pread_query(), process_records)
-                .unwrap();
-            let rb = builder.build().unwrap();
-
-            // Attach program to event
-            let link = skel.progs_mut().pread_query().attach().unwrap();
-
-            (rb, link)
-        }
-        "opt" => {
-            log::info!("starting opt probe");
-            let mut skel = opt::PreadQueryNextSkelBuilder::default()
-                // let mut skel = opt::PreadQuerySkelBuilder::default()
-                .open()
-                .unwrap()
-                .load()
-                .unwrap();
-            // Create rb handler
-            let maps = skel.maps();
-            let mut builder = RingBufferBuilder::new();
-            builder
-                .add(&maps.ring_buf_pread_query(), process_records)
-                .unwrap();
-            let rb = builder.build().unwrap();
-            // Attach program to event
-            let link = skel.progs_mut().pread_query().attach().unwrap();
-
-            (rb, link)
+    if args.transport == Transport::Mappoll && args.validate {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--validate reads the probe's output buffer; map polling has none",
+            )
+            .exit();
+    }
+    // Map polling drains whole tumbling windows, and the perf buffer splits a
+    // window's rows across samples, which pane merging can't tell apart
+    if args.transport != Transport::Ringbuf
+        && window_spec(&args).is_some_and(|w| !matches!(w, WindowSpec::Tumbling(_)))
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "--transport {} supports tumbling windows only",
+                    args.transport
+                ),
+            )
+            .exit();
+    }
+    if args.validate && args.consume == Consume::Inline {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--validate consumes both probes on threads of their own; drop --consume inline",
+            )
+            .exit();
+    }
+    if args.group_by != GroupBy::default() && args.validate {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--validate compares groups by fd and cpu; leave --group-by at fd,cpu",
+            )
+            .exit();
+    }
+    if let Some(window) = window_spec(&args) {
+        if let Err(e) = window.validate() {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("{e:#}"))
+                .exit();
         }
-        "unopt" => {
-            log::info!("starting unopt probe");
-            let mut skel = unopt::PreadQuerySkelBuilder::default()
-                .open()
-                .unwrap()
-                .load()
-                .unwrap();
-            // Create rb handler
-            let maps = skel.maps();
-            let mut builder = RingBufferBuilder::new();
-            builder
-                .add(&maps.ring_buf_pread_query(), process_records_raw)
-                .unwrap();
-            let rb = builder.build().unwrap();
-            // Attach program to event
-            let link = skel.progs_mut().pread_query().attach().unwrap();
-
-            (rb, link)
+        if !matches!(window, WindowSpec::Tumbling(_)) && args.validate {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--validate compares tumbling windows only",
+                )
+                .exit();
         }
-        _ => panic!("Probe type {} not supported", probe_type),
-    };
-
-    // Spawn thread to continuously poll
-    thread::spawn(move || while rb.poll(Duration::MAX).is_ok() {});
+    }
+    args
+}
 
-    let mut n_records = 0;
-    let now = Instant::now();
+/// Exits with a usage error if `args` use an option that the probe
+/// `probe_type` does not support, naming the probes that do.
+fn check_capabilities(registry: &ProbeRegistry, probe_type: &str, args: &Args) {
+    let Some(entry) = registry.get(probe_type) else {
+        return;
+    };
+    let windows = window_spec(args).is_some_and(|w| !matches!(w, WindowSpec::Tumbling(_)));
+    let options: [(bool, &str, Supports); 8] = [
+        (args.validate, "--validate", |c| c.validate.is_some()),
+        (!args.aggs.is_empty(), "--aggs", |c| c.aggs),
+        (args.group_by != GroupBy::default(), "--group-by", |c| {
+            c.group_by
+        }),
+        (
+            args.transport == Transport::Ringbuf,
+            "--transport ringbuf (the default)",
+            |c| !c.mappoll_only,
+        ),
+        (args.ringbuf_bytes.is_some(), "--ringbuf-bytes", |c| {
+            !c.mappoll_only
+        }),
+        (
+            args.transport == Transport::Perfbuf,
+            "--transport perfbuf",
+            |c| c.perfbuf,
+        ),
+        (
+            args.transport == Transport::Mappoll,
+            "--transport mappoll",
+            |c| c.mappoll,
+        ),
+        (windows, "sliding, count and session windows", |c| c.windows),
+    ];
+    for (used, option, supports) in options {
+        if !used || supports(&entry.capabilities) {
+            continue;
+        }
+        let mut probes = registry.supporting(supports);
+        let last = probes.pop().unwrap_or_default();
+        let probes = if probes.is_empty() {
+            last.to_string()
+        } else {
+            format!("{} or {last}", probes.join(", "))
+        };
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!("{option} needs the {probes} probe"),
+            )
+            .exit();
+    }
+}
 
-    match probe_type.as_str() {
-        "opt" | "ebql" => {
-            loop {
-                if done.load(SeqCst) {
-                    break;
-                }
-                if let Ok(records) = rx.recv_timeout(Duration::from_millis(100)) {
-                    println!("num records: {}", records.len());
-                    n_records += records.len();
-                }
+/// The query window selected by the window arguments, if any.
+fn window_spec(args: &Args) -> Option<WindowSpec> {
+    if let Some(gap) = args.session_gap_ms {
+        return Some(WindowSpec::Session {
+            gap: Duration::from_millis(gap),
+        });
+    }
+    if let Some(size) = args.window_count {
+        return Some(WindowSpec::Count {
+            size,
+            step: args.window_count_step.unwrap_or(size),
+        });
+    }
+    let size = Duration::from_millis(args.window_ms?);
+    Some(match args.window_step_ms {
+        Some(step) => {
+            WindowSpec::Sliding {
+                size,
+                step: Duration::from_millis(step),
             }
         }
-        "unopt" => {
-            let mut total_records = 0;
-            let mut last_seen = 0;
-            let sec_ns = Duration::from_secs(1).as_nanos() as u64;
-            // Map of (fd, cpu) -> (count, max, avg (repr as sum))
-            let mut aggs = BTreeMap::new();
-            loop {
-                if done.load(SeqCst) {
-                    break;
-                }
-                if let Ok(records) = rx1.recv_timeout(Duration::from_millis(100)) {
-                    total_records += records.len();
-                    let mut seen = 0;
-                    for r in records {
-                        let agg = match aggs.entry((r.fd, r.cpu)) {
-                            Entry::Occupied(v) => v.into_mut(),
-                            Entry::Vacant(v) => v.insert((0, 0, 0)),
-                        };
-                        agg.0 += 1;
-                        if r.count > agg.1 {
-                            agg.1 = r.count;
-                        }
-                        agg.2 += r.count;
-                        seen = r.time;
-                    }
-                    if last_seen == 0 {
-                        last_seen = seen;
-                    }
-                    if seen - last_seen > sec_ns {
-                        last_seen = seen;
-                        n_records += aggs.len();
-                        aggs.clear();
-                    }
-                }
-            }
+        None => WindowSpec::Tumbling(size),
+    })
+}
 
-            println!("Got {} total records", total_records);
+fn main() {
+    let registry = probes::registry();
+    let args = parse_args(&registry);
+    if args.list_probes {
+        for entry in registry.entries() {
+            println!("{:<8}{}", entry.name, entry.description);
         }
-        _ => panic!("Probe type {} not supported", probe_type),
+        return;
+    }
+    let probe_type = args.probe_type.clone().unwrap().to_lowercase();
+    let mut probe = registry.create(&probe_type).unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    init_signal(done.clone());
+    bpf_prog::bump_memlock_rlimit().unwrap();
+    bpf_prog::init_log(log::LevelFilter::Trace);
+    if args.bpf_trace_pipe {
+        if args.bpf_log_level == BpfLogLevel::Off {
+            log::warn!("--bpf-trace-pipe has nothing to show with --bpf-log-level off");
+        }
+        bpf_log::spawn_trace_pipe_reader().unwrap();
+    }
+    let stats_guard = bpf_stats::enable_bpf_stats().unwrap();
+    if stats_guard.already_enabled() {
+        log::warn!("BPF stats were already enabled; other programs may be adding overhead");
+    }
+
+    log::info!("starting {} probe", probe_type);
+    let now = Instant::now();
+    let opts = RunOptions {
+        samples_path: args.bpf_samples_path.clone(),
+        sample_interval: Duration::from_millis(args.bpf_sample_interval_ms),
+        window: window_spec(&args),
+        max_groups: args.max_groups,
+        ringbuf_bytes: args.ringbuf_bytes,
+        transport: args.transport,
+        consume: args.consume,
+        bpf_log_level: args.bpf_log_level,
+        filter: TaskFilter {
+            pid: args.pid,
+            tgid: args.tgid,
+            cgroup: args.cgroup.as_deref().map(|cgroup| {
+                TaskFilter::cgroup_id(cgroup).unwrap_or_else(|e| {
+                    Args::command()
+                        .error(ErrorKind::ValueValidation, format!("--cgroup: {e:#}"))
+                        .exit()
+                })
+            }),
+        },
+        group_by: args.group_by,
+        aggs: args.aggs,
+        syscalls: args.syscalls.clone(),
+    };
+    if opts.filter.process().is_none() {
+        log::info!("no --tgid or --pid given; results are grouped by fd, not by file");
+    }
+    let (output, mut validation) = if args.validate {
+        let entry = registry.get(&probe_type).unwrap();
+        let validate = entry.capabilities.validate.unwrap();
+        let validation = validate(done, &opts, args.validate_slack).unwrap();
+        let output = RunOutput {
+            records: validation.kernel_rows,
+            ..Default::default()
+        };
+        (output, Some(validation))
+    } else {
+        (probe.run(done, &opts).unwrap(), None)
     };
     println!(
         "Stopped probing. Records: {}\tTime elapsed: {:?}",
-        n_records,
+        output.records,
         now.elapsed()
     );
+    if !output.files.is_empty() {
+        for (kind, preads, bytes) in files::totals_by_kind(&output.files) {
+            if preads > 0 {
+                println!("{kind} files: preads: {preads}\trequested bytes: {bytes}");
+            }
+        }
+        for f in output.files.iter().take(MAX_FILES_SHOWN) {
+            println!(
+                "  {}\tpreads: {}\trequested bytes: {}\tmax request: {}",
+                f.path, f.preads, f.requested_bytes, f.max_request
+            );
+        }
+    }
 
-    let stat = ProgStats::get();
+    let mut report = RunReport::new("ebpf-probe-pread", args.run_id.clone(), &args).unwrap();
+    let stat = &report.prog_stats;
     println!(
         "utime: {}\tstime: {}\tticks per second: {}\tProgram runtime: {}",
         stat.utime, stat.stime, stat.clock_tps, stat.runtime,
     );
 
-    // Get BPF stats
-    let progs = bpf_stats::get_bpf_stats();
-    if !progs.is_empty() {
-        for prog in &progs {
-            println!(
-                "runtime ns: {}\trun count: {}\trecursion misses: {}",
-                prog.run_time_ns, prog.run_cnt, prog.recursion_misses
-            );
+    // Get BPF stats; a validation run loads its own probes
+    let errors = match &mut validation {
+        Some(v) => {
+            report.bpf_programs = mem::take(&mut v.prog_stats);
+            v.bpf_errors
+        }
+        None => {
+            report.bpf_programs = probe.prog_stats().unwrap();
+            probe.bpf_errors().unwrap()
         }
+    };
+    for prog in &report.bpf_programs {
+        println!(
+            "program: {} (id {})\truntime ns: {}\trun count: {}\trecursion misses: {}",
+            prog.name, prog.id, prog.run_time_ns, prog.run_cnt, prog.recursion_misses
+        );
     }
+    println!("BPF errors: {errors}");
+    if errors.lost_results() {
+        log::warn!("the probe lost results; see the BPF errors above");
+    }
+    report.counts.extend(errors.counts());
+    report.counts.insert("records", output.records as u64);
+    report.counts.extend(output.counts);
+    report.files = output.files;
 
-    if !args.stats_path.is_empty() {
-        let mut f = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(args.stats_path.clone())
-            .unwrap();
-        write!(f, "{}\n", stat).unwrap();
-
-        if !progs.is_empty() {
-            // Write bpf stats to separate file
-            let bpf_path = format!("{}-bpf", args.stats_path);
-            f = fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(bpf_path)
-                .unwrap();
-            write!(f, "{}, {}\n", progs[0].run_time_ns, progs[0].run_cnt).unwrap();
+    if let Some(v) = &validation {
+        println!(
+            "Validation: {} mismatches over {} groups in {} windows (kernel: {} windows, \
+             reference: {} windows)",
+            v.mismatches.len(),
+            v.groups_compared,
+            v.windows_compared,
+            v.kernel_windows,
+            v.reference_windows
+        );
+        for m in v.mismatches.iter().take(MAX_MISMATCHES_SHOWN) {
+            println!("  {m}");
         }
+        if v.mismatches.len() > MAX_MISMATCHES_SHOWN {
+            println!("  ... {} more", v.mismatches.len() - MAX_MISMATCHES_SHOWN);
+        }
+        report
+            .counts
+            .insert("validated_windows", v.windows_compared as u64);
+        report
+            .counts
+            .insert("validated_groups", v.groups_compared as u64);
+        report
+            .counts
+            .insert("validation_mismatches", v.mismatches.len() as u64);
+    }
+
+    if !args.stats_path.is_empty() {
+        report.write(&args.stats_path).unwrap();
+    }
+    if validation.is_some_and(|v| !v.mismatches.is_empty()) {
+        // exit skips destructors; restore the BPF stats setting first
+        drop(stats_guard);
+        process::exit(1);
     }
 }
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+
+use common::{
+    aggs::Aggs,
+    bpf_structs::{
+        PreadGroupKey, PreadLatencyRecord, PreadQueryRecord, RawPreadRecord, SyscallQueryRecord,
+    },
+    files::{FileGroups, FileStats},
+    group_by::GroupBy,
+    probe::Consumer,
+    syscall::Syscall,
+    window::{ClosedWindow, PaneMerger, Panes, PreadQueryAgg, WindowSpec},
+};
+
+/// Consumer for probes that aggregate in the kernel; only counts the rows it
+/// receives.
+#[derive(Default)]
+pub struct RecordCounter {
+    n_records: usize,
+}
+
+impl<T> Consumer<T> for RecordCounter {
+    fn consume(&mut self, records: &[T]) {
+        log::debug!("num records: {}", records.len());
+        self.n_records += records.len();
+    }
+
+    fn records(&self) -> usize {
+        self.n_records
+    }
+}
+
+/// Consumer for the pread query rows: counts them like [`RecordCounter`] and,
+/// if they are grouped by fd, totals them per file, resolving fds against the
+/// process `pid` if given. Rows are logged at debug level with their group,
+/// and the optional aggregates in `aggs` are totaled over the run.
+///
+/// With more than one pane per `window`, each batch of rows is a pane of a
+/// sliding or count window: totals are taken over the panes, while the rows
+/// counted and logged are the windows merged from the recent panes. Rows from
+/// the kernel carry the start of their pane, so its panes leave sliding
+/// windows once too old just like those closed in userspace with
+/// [`FileGrouper::consume_pane`].
+pub struct FileGrouper {
+    rows: RecordCounter,
+    group_by: GroupBy,
+    files: Option<FileGroups>,
+    aggs: Aggs,
+    total: PreadQueryRecord,
+    merger: Option<PaneMerger<PreadGroupKey, PreadQueryRecord>>,
+    /// Length of time panes in nanoseconds; zero for count and session panes,
+    /// whose end the rows don't tell.
+    pane_ns: u64,
+}
+
+impl FileGrouper {
+    pub fn new(pid: Option<u32>, group_by: GroupBy, aggs: Aggs, window: WindowSpec) -> Self {
+        Self {
+            rows: RecordCounter::default(),
+            group_by,
+            files: group_by.contains(GroupBy::FD).then(|| FileGroups::new(pid)),
+            aggs,
+            total: PreadQueryRecord::default(),
+            merger: (window.panes() > 1).then(|| PaneMerger::new(window)),
+            pane_ns: match window {
+                WindowSpec::Tumbling(step) | WindowSpec::Sliding { step, .. } => {
+                    step.as_nanos() as u64
+                }
+                WindowSpec::Count { .. } | WindowSpec::Session { .. } => 0,
+            },
+        }
+    }
+
+    /// Takes in the rows of a pane that covered `[start, end)` in nanoseconds.
+    pub fn consume_pane(&mut self, start: u64, end: u64, records: &[PreadQueryRecord]) {
+        if let Some(files) = &mut self.files {
+            files.add_window(records);
+        }
+        if !self.aggs.is_empty() {
+            for r in records {
+                self.total.merge(r);
+            }
+        }
+        let merged = self.merge_pane(start, end, records);
+        let records = merged.as_deref().unwrap_or(records);
+        for r in records {
+            log::debug!(
+                "{}: count {} max {} avg {}",
+                self.group_by.key(r),
+                r.count,
+                r.max_count,
+                r.avg_count
+            );
+        }
+        self.rows.consume(records);
+    }
+
+    /// Window merged from the recent panes, or `None` with one pane per window.
+    fn merge_pane(
+        &mut self,
+        start: u64,
+        end: u64,
+        records: &[PreadQueryRecord],
+    ) -> Option<Vec<PreadQueryRecord>> {
+        let merger = self.merger.as_mut()?;
+        let pane = ClosedWindow {
+            start,
+            end,
+            groups: records.iter().map(|r| (r.group_key(), *r)).collect(),
+        };
+        Some(merger.push(pane).groups.into_values().collect())
+    }
+}
+
+impl Consumer<PreadQueryRecord> for FileGrouper {
+    fn consume(&mut self, records: &[PreadQueryRecord]) {
+        for pane in records.chunk_by(|a, b| a.window_start == b.window_start) {
+            let start = pane[0].window_start;
+            self.consume_pane(start, start + self.pane_ns, pane);
+        }
+    }
+
+    fn records(&self) -> usize {
+        self.rows.n_records
+    }
+
+    fn files(&self) -> Vec<FileStats> {
+        self.files.as_ref().map_or_else(Vec::new, FileGroups::files)
+    }
+
+    fn counts(&self) -> BTreeMap<&'static str, u64> {
+        let t = &self.total;
+        let mut counts = BTreeMap::new();
+        if self.aggs.contains(Aggs::MIN) {
+            counts.insert("count_min", t.min_count);
+        }
+        if self.aggs.contains(Aggs::SUM) {
+            counts.insert("count_sum", t.sum_count);
+        }
+        if self.aggs.contains(Aggs::VARIANCE) {
+            counts.insert("count_variance", t.variance().round() as u64);
+        }
+        if self.aggs.contains(Aggs::DISTINCT) {
+            counts.insert("count_distinct", t.distinct_estimate().round() as u64);
+        }
+        counts
+    }
+
+    fn finish(&mut self) {
+        if self.aggs.is_empty() {
+            return;
+        }
+        for (name, value) in self.counts() {
+            println!("{name}: {value}");
+        }
+        if self.aggs.contains(Aggs::HIST) {
+            print_log2_hist(&self.total.count_hist, "bytes");
+        }
+    }
+}
+
+/// Consumer for the unopt probe, which aggregates raw preads in userspace into
+/// the same windows as the ebql probe and emits the same rows.
+pub struct UnoptAggregator {
+    total_records: usize,
+    window: Panes<(u64, u64), PreadQueryAgg>,
+    rows: FileGrouper,
+}
+
+impl UnoptAggregator {
+    pub fn new(window: WindowSpec, pid: Option<u32>, aggs: Aggs) -> Self {
+        Self {
+            total_records: 0,
+            window: Panes::new(window),
+            rows: FileGrouper::new(pid, GroupBy::default(), aggs, window),
+        }
+    }
+}
+
+impl Consumer<RawPreadRecord> for UnoptAggregator {
+    fn consume(&mut self, records: &[RawPreadRecord]) {
+        self.total_records += records.len();
+        for r in records {
+            if let Some(closed) = self.window.add(r.time, (r.fd, r.cpu), r.count) {
+                self.rows
+                    .consume_pane(closed.start, closed.end, &closed.records());
+            }
+        }
+    }
+
+    fn records(&self) -> usize {
+        self.rows.records()
+    }
+
+    fn files(&self) -> Vec<FileStats> {
+        self.rows.files()
+    }
+
+    fn counts(&self) -> BTreeMap<&'static str, u64> {
+        self.rows.counts()
+    }
+
+    fn finish(&mut self) {
+        if let Some(closed) = self.window.flush() {
+            self.rows
+                .consume_pane(closed.start, closed.end, &closed.records());
+        }
+        println!("Got {} total records", self.total_records);
+        self.rows.finish();
+    }
+}
+
+/// Consumer for the latency probe: counts rows like [`RecordCounter`] and
+/// merges every window into a histogram over the whole run, printed when
+/// probing stops.
+#[derive(Default)]
+pub struct LatencyHistogram {
+    rows: RecordCounter,
+    total: PreadLatencyRecord,
+}
+
+impl Consumer<PreadLatencyRecord> for LatencyHistogram {
+    fn consume(&mut self, records: &[PreadLatencyRecord]) {
+        for r in records {
+            let total = &mut self.total;
+            total.count += r.count;
+            total.errors += r.errors;
+            total.bytes += r.bytes;
+            total.latency_sum_ns += r.latency_sum_ns;
+            total.latency_max_ns = total.latency_max_ns.max(r.latency_max_ns);
+            for (bucket, n) in total.latency_hist.iter_mut().zip(r.latency_hist) {
+                *bucket += n;
+            }
+        }
+        self.rows.consume(records);
+    }
+
+    fn records(&self) -> usize {
+        self.rows.n_records
+    }
+
+    fn finish(&mut self) {
+        let t = &self.total;
+        println!(
+            "preads: {}\terrors: {}\tbytes read: {}\tavg latency ns: {}\tmax latency ns: {}",
+            t.count,
+            t.errors,
+            t.bytes,
+            t.latency_sum_ns.checked_div(t.count).unwrap_or(0),
+            t.latency_max_ns
+        );
+        print_log2_hist(&t.latency_hist, "ns");
+    }
+}
+
+/// Consumer for the syscall probe: counts rows like [`RecordCounter`] and
+/// totals calls and bytes per syscall, printed when probing stops.
+#[derive(Default)]
+pub struct SyscallTotals {
+    rows: RecordCounter,
+    /// (calls, bytes) per syscall id.
+    totals: BTreeMap<u64, (u64, u64)>,
+}
+
+impl Consumer<SyscallQueryRecord> for SyscallTotals {
+    fn consume(&mut self, records: &[SyscallQueryRecord]) {
+        for r in records {
+            let (calls, bytes) = self.totals.entry(r.syscall).or_default();
+            *calls += r.count;
+            *bytes = bytes.saturating_add(r.count.saturating_mul(r.avg_count));
+        }
+        self.rows.consume(records);
+    }
+
+    fn records(&self) -> usize {
+        self.rows.n_records
+    }
+
+    fn finish(&mut self) {
+        for (&id, (calls, bytes)) in &self.totals {
+            let name =
+                Syscall::from_id(id).map_or_else(|| format!("syscall {id}"), |s| s.to_string());
+            println!("{name}: calls: {calls}\tbytes: {bytes}");
+        }
+    }
+}
+
+/// Prints a log2 histogram, where bucket `i` covers `[2^i, 2^(i+1))`, in the
+/// style of the bcc tools. Empty buckets at either end are left out.
+fn print_log2_hist(hist: &[u64], unit: &str) {
+    const WIDTH: u64 = 40;
+    let Some(first) = hist.iter().position(|&n| n > 0) else {
+        return;
+    };
+    let last = hist.iter().rposition(|&n| n > 0).unwrap();
+    let max = hist.iter().copied().max().unwrap();
+    println!(
+        "{:>24} : {:<10} distribution",
+        format!("{unit} range"),
+        "count"
+    );
+    for (i, &n) in hist.iter().enumerate().take(last + 1).skip(first) {
+        let low = if i == 0 { 0 } else { 1u64 << i };
+        let high = (1u64 << (i + 1)) - 1;
+        let stars = (n * WIDTH).div_ceil(max) as usize;
+        println!(
+            "{:>11} -> {:<10} : {:<10} |{:<40}|",
+            low,
+            high,
+            n,
+            "*".repeat(stars)
+        );
+    }
+}
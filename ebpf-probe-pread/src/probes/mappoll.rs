@@ -0,0 +1,196 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use common::{
+    bpf_prog::{drain_hash_map, drain_percpu_hash_map},
+    bpf_structs::{AvgAgg, ExtAgg, PercpuPreadAgg, PercpuPreadKey, PreadGroupBy, PreadQueryRecord},
+};
+
+use super::{EbqlProbe, PercpuProbe, DEFAULT_WINDOW};
+
+/// How long after a flip the ebql probe's set of maps flipped away from is left
+/// to preads still being added to it before it is drained.
+const FLIP_GRACE: Duration = Duration::from_millis(10);
+
+/// How long after its end a percpu window is left to preads still being added
+/// to it on other CPUs before it is drained.
+const PERCPU_GRACE: Duration = Duration::from_millis(10);
+
+/// Map polling state of the ebql probe between drains.
+#[derive(Default)]
+pub(super) struct EbqlWindows {
+    /// Start of the window preads go to
+    window_start: Option<Instant>,
+    /// Set of maps flipped away from and when, until it is drained
+    flipped: Option<(u32, Instant)>,
+}
+
+/// Closes the ebql probe's window once it is over, or right away if `force`,
+/// by flipping the set of aggregation maps preads go to, and drains the set
+/// flipped away from once it has been left for [`FLIP_GRACE`]. With map
+/// polling the kernel keeps no window state: a window is whatever reached a
+/// set between two flips, on a grid of the window length from the first
+/// flush.
+pub(super) fn drain_ebql(probe: &mut EbqlProbe, force: bool) -> Result<Vec<Vec<PreadQueryRecord>>> {
+    let mut windows: Vec<_> = drain_flipped(probe, force)?.into_iter().collect();
+    // The set preads went to before the last flip is still being left
+    if probe.drain_state.flipped.is_some() {
+        return Ok(windows);
+    }
+    let window = probe.opts.tumbling_window().unwrap_or(DEFAULT_WINDOW);
+    let now = Instant::now();
+    let start = *probe.drain_state.window_start.get_or_insert(now);
+    let elapsed = now.duration_since(start);
+    if !force && elapsed < window {
+        return Ok(windows);
+    }
+    probe.drain_state.window_start =
+        Some(start + window * (elapsed.as_nanos() / window.as_nanos()) as u32);
+
+    let skel = probe.skel.as_mut().context("Probe was not loaded")?;
+    let set = skel.bss().mappoll_set;
+    skel.bss_mut().mappoll_set = set ^ 1;
+    probe.drain_state.flipped = Some((set, Instant::now()));
+    if force {
+        windows.extend(drain_flipped(probe, true)?);
+    }
+    Ok(windows)
+}
+
+/// Drains the set of maps flipped away from, if any, once preads still being
+/// added to it are done, waiting for them if `force`. Returns its rows unless
+/// there are none, as the kernel emits nothing for windows without preads.
+fn drain_flipped(probe: &mut EbqlProbe, force: bool) -> Result<Option<Vec<PreadQueryRecord>>> {
+    let Some((set, flipped)) = probe.drain_state.flipped else {
+        return Ok(None);
+    };
+    if !force && flipped.elapsed() < FLIP_GRACE {
+        return Ok(None);
+    }
+    thread::sleep(FLIP_GRACE.saturating_sub(flipped.elapsed()));
+    probe.drain_state.flipped = None;
+
+    let skel = probe.skel.as_ref().context("Probe was not loaded")?;
+    let maps = skel.maps();
+    let (count, max, avg, ext) = if set == 0 {
+        (
+            maps.count__pread_query(),
+            maps.max_count_pread_query(),
+            maps.avg_count_pread_query(),
+            maps.ext_count_pread_query(),
+        )
+    } else {
+        (
+            maps.count__pread_query_1(),
+            maps.max_count_pread_query_1(),
+            maps.avg_count_pread_query_1(),
+            maps.ext_count_pread_query_1(),
+        )
+    };
+    // No pread adds to the set anymore, so every group is in all of its maps
+    let mut rows: HashMap<PreadGroupBy, PreadQueryRecord> = HashMap::new();
+    for (key, count) in drain_hash_map::<PreadGroupBy, u64>(count)? {
+        let mut row = key.record();
+        row.count = count;
+        rows.insert(key, row);
+    }
+    for (key, max) in drain_hash_map::<PreadGroupBy, u64>(max)? {
+        if let Some(row) = rows.get_mut(&key) {
+            row.max_count = max;
+        }
+    }
+    for (key, avg) in drain_hash_map::<PreadGroupBy, AvgAgg>(avg)? {
+        if let Some(row) = rows.get_mut(&key).filter(|_| avg.count > 0) {
+            row.avg_count = avg.val / avg.count;
+        }
+    }
+    let selected = probe.opts.aggs;
+    if !selected.is_empty() {
+        for (key, ext) in drain_hash_map::<PreadGroupBy, ExtAgg>(ext)? {
+            if let Some(row) = rows.get_mut(&key) {
+                row.set_ext_agg(&ext, selected);
+            }
+        }
+    }
+    if rows.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(rows.into_values().collect()))
+}
+
+/// Rows of the percpu probe drained but not returned yet.
+#[derive(Default)]
+pub(super) struct PercpuWindows {
+    /// Windows before this one were returned
+    next: u64,
+    /// Rows by window; later drains of a window are merged into them until
+    /// the window is returned.
+    windows: BTreeMap<u64, HashMap<PercpuPreadKey, PreadQueryRecord>>,
+}
+
+/// Drains the percpu probe's map, merging the entries across CPUs, once a
+/// window has been over for [`PERCPU_GRACE`], or right away if `force`, and
+/// returns the windows that are over, oldest first. The map is drained
+/// atomically per entry, so no pread is lost; one added to a window after it
+/// was returned shows up as a second, partial copy of that window.
+pub(super) fn drain_percpu(
+    probe: &mut PercpuProbe,
+    force: bool,
+) -> Result<Vec<Vec<PreadQueryRecord>>> {
+    let window_ns = probe
+        .opts
+        .tumbling_window()
+        .unwrap_or(DEFAULT_WINDOW)
+        .as_nanos() as u64;
+    let skel = probe.skel.as_ref().context("Probe was not loaded")?;
+    let epoch = skel.bss().window_epoch;
+    if epoch == 0 {
+        return Ok(Vec::new());
+    }
+    // Windows before this one are over
+    let open = (monotonic_ns().saturating_sub(PERCPU_GRACE.as_nanos() as u64))
+        .saturating_sub(epoch)
+        / window_ns;
+    let state = &mut probe.drain_state;
+    if !force && open <= state.next {
+        return Ok(Vec::new());
+    }
+    state.next = open;
+
+    let maps = skel.maps();
+    let entries = drain_percpu_hash_map::<PercpuPreadKey, PercpuPreadAgg>(maps.aggs_pread_query())?;
+    for (key, aggs) in entries {
+        let record = key.record(&aggs, probe.opts.aggs);
+        state
+            .windows
+            .entry(key.window)
+            .or_default()
+            .entry(key)
+            .and_modify(|r| r.merge(&record))
+            .or_insert(record);
+    }
+    let open_windows = if force {
+        BTreeMap::new()
+    } else {
+        state.windows.split_off(&open)
+    };
+    let closed = mem::replace(&mut state.windows, open_windows);
+    Ok(closed
+        .into_values()
+        .map(|groups| groups.into_values().collect())
+        .collect())
+}
+
+/// Current `CLOCK_MONOTONIC` time, the clock of `bpf_ktime_get_ns`.
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
@@ -0,0 +1,508 @@
+mod consumers;
+mod mappoll;
+mod validate;
+
+mod pread_query {
+    pub mod ebql {
+        include!(concat!(env!("OUT_DIR"), "/ebql_pread_query.skel.rs"));
+    }
+
+    pub mod opt {
+        include!(concat!(env!("OUT_DIR"), "/opt_pread_query_next.skel.rs"));
+    }
+
+    pub mod unopt {
+        include!(concat!(env!("OUT_DIR"), "/unopt_pread_query.skel.rs"));
+    }
+
+    pub mod percpu {
+        include!(concat!(env!("OUT_DIR"), "/percpu_pread_query.skel.rs"));
+    }
+
+    pub mod latency {
+        include!(concat!(env!("OUT_DIR"), "/latency_pread_latency.skel.rs"));
+    }
+}
+
+mod syscall_query {
+    pub mod syscall {
+        include!(concat!(env!("OUT_DIR"), "/syscall_syscall_query.skel.rs"));
+    }
+}
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use common::{
+    bpf_errors::ERROR_COUNTS_MAP,
+    bpf_prog,
+    bpf_structs::{
+        AvgAgg, Decoder, ExtAgg, PercpuPreadAgg, PercpuPreadKey, PreadGroupBy, PreadLatencyRecord,
+        PreadQueryRecord, PreadQueryRow, PreadQueryWideRow, RawPreadRecord, SyscallQueryRecord,
+    },
+    probe::{Capabilities, Consumer, Probe, ProbeRegistry, RunOptions, SampleCallback},
+    transport::{Output, Transport, PERF_BUF_MAP},
+    window::WindowSpec,
+};
+use consumers::{FileGrouper, LatencyHistogram, SyscallTotals, UnoptAggregator};
+use libbpf_rs::{
+    skel::{OpenSkel, SkelBuilder},
+    Link, Map, Program, RingBufferBuilder,
+};
+use pread_query::*;
+use syscall_query::*;
+
+// Size and offset checks of the record types against the BPF structs.
+include!(concat!(env!("OUT_DIR"), "/record_layouts.rs"));
+
+/// Window length of the pread query when `--window-ms` is not given.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Implements [`Probe`] for a pread skeleton: every program left to autoload is
+/// attached to its tracepoint. `consumer` builds the probe's consumer from the
+/// run options and `configure` applies the probe-specific load-time
+/// parameters to the open skeleton.
+///
+/// The optional arguments: `ringbuf` is the output map of the ring buffer
+/// transport, which probes without one can't use. `capabilities` are the run
+/// options the probe supports beyond the defaults. `decoder` builds the
+/// decoder of the records sent from the run options, for probes that don't
+/// send the record type as it is. `flush` is the skeleton's window flush
+/// program, run on every CPU if `per_cpu`, and `drain` reads the windows from
+/// the maps instead with map polling, keeping its state between drains in a
+/// `drain_state` field of type `state`.
+macro_rules! skel_probe {
+    (
+        $probe:ident,
+        $builder:ty,
+        $open_skel:ty,
+        $skel:ty,
+        $record:ty,
+        $(ringbuf: $ringbuf:ident,)?
+        $(capabilities: $caps:expr,)?
+        consumer: |$copts:ident| $consumer:expr,
+        configure: |$open:ident, $opts:ident| $configure:block
+        $(, decoder: |$dopts:ident| $decoder:expr)?
+        $(, flush: $flush:ident, per_cpu: $per_cpu:expr)?
+        $(, drain: $drain:path, state: $state:ty)?
+    ) => {
+        #[derive(Default)]
+        pub struct $probe {
+            open_skel: Option<$open_skel>,
+            skel: Option<$skel>,
+            links: Vec<Link>,
+            opts: RunOptions,
+            $(
+                #[doc = concat!("State `", stringify!($drain), "` keeps between drains")]
+                drain_state: $state,
+            )?
+        }
+
+        impl Probe for $probe {
+            type Record = $record;
+
+            $(
+                fn capabilities() -> Capabilities {
+                    $caps
+                }
+            )?
+
+            fn open(&mut self) -> Result<()> {
+                self.open_skel = Some(<$builder>::default().open()?);
+                Ok(())
+            }
+
+            fn configure(&mut self, $opts: &RunOptions) -> Result<()> {
+                let $open = self.open_skel.as_mut().context("Probe was not opened")?;
+                let rodata = $open.rodata_mut();
+                rodata.LOG_LVL = $opts.bpf_log_level as u8;
+                rodata.FILTER_PID = $opts.filter.pid.unwrap_or(0);
+                rodata.FILTER_TGID = $opts.filter.tgid.unwrap_or(0);
+                rodata.FILTER_CGROUP = $opts.filter.cgroup.unwrap_or(0);
+                rodata.TRANSPORT = $opts.transport as u8;
+                $(
+                    if let Some(bytes) = $opts.ringbuf_bytes {
+                        $open
+                            .maps_mut()
+                            .$ringbuf()
+                            .set_max_entries(bytes)?;
+                    }
+                )?
+                $configure
+                self.opts = $opts.clone();
+                Ok(())
+            }
+
+            fn load(&mut self) -> Result<()> {
+                let open_skel = self.open_skel.take().context("Probe was not opened")?;
+                self.skel = Some(open_skel.load()?);
+                Ok(())
+            }
+
+            fn attach(&mut self) -> Result<()> {
+                let skel = self.skel.as_mut().context("Probe was not loaded")?;
+                // Bare raw_tp programs have nothing to attach to; they are run
+                // from userspace
+                let progs = skel.obj.progs_iter_mut();
+                for prog in progs.filter(|p| p.autoload() && p.section() != "raw_tp") {
+                    self.links.push(prog.attach()?);
+                }
+                Ok(())
+            }
+
+            fn output(&self, callback: SampleCallback) -> Result<Option<Output>> {
+                let skel = self.skel.as_ref().context("Probe was not loaded")?;
+                let output = match self.opts.transport {
+                    Transport::Ringbuf => skel_probe!(@ringbuf skel, callback $(, $ringbuf)?),
+                    Transport::Mappoll => return Ok(None),
+                    Transport::Perfbuf => {
+                        let map = skel.obj.map(PERF_BUF_MAP).context("Probe has no perf buffer")?;
+                        Output::perf_buffer(map, self.decoder().record_size, callback)?
+                    }
+                };
+                Ok(Some(output))
+            }
+
+            fn consumer(&self) -> Box<dyn Consumer<Self::Record>> {
+                let $copts = &self.opts;
+                Box::new($consumer)
+            }
+
+            $(
+                fn decoder(&self) -> Decoder<Self::Record> {
+                    let $dopts = &self.opts;
+                    $decoder
+                }
+            )?
+
+            fn programs(&self) -> Vec<&Program> {
+                self.skel
+                    .as_ref()
+                    .map_or_else(Vec::new, |skel| {
+                        skel.obj.progs_iter().filter(|p| p.autoload()).collect()
+                    })
+            }
+
+            fn error_counts(&self) -> Option<&Map> {
+                self.skel.as_ref()?.obj.map(ERROR_COUNTS_MAP)
+            }
+
+            skel_probe!(@flush [$($flush, $per_cpu)?] [$($drain)?]);
+        }
+    };
+    (@ringbuf $skel:ident, $callback:ident) => {
+        bail!("Probe has no ring buffer")
+    };
+    (@ringbuf $skel:ident, $callback:ident, $ringbuf:ident) => {{
+        let maps = $skel.maps();
+        let mut builder = RingBufferBuilder::new();
+        builder.add(maps.$ringbuf(), $callback)?;
+        Output::Ringbuf(builder.build()?)
+    }};
+    (@flush [] []) => {};
+    (@flush [$($flush:ident, $per_cpu:expr)?] [$($drain:path)?]) => {
+        fn flush(&mut self, force: bool) -> Result<Vec<Vec<Self::Record>>> {
+            $(
+                if self.opts.transport == Transport::Mappoll {
+                    return $drain(self, force);
+                }
+            )?
+            $(
+                let skel = self.skel.as_ref().context("Probe was not loaded")?;
+                flush_windows(skel.progs().$flush(), force, $per_cpu)?;
+            )?
+            Ok(Vec::new())
+        }
+    };
+}
+
+/// Runs a window flush program once, or once on each CPU if `per_cpu`. The
+/// program closes its window if due, or regardless if `force` is set.
+fn flush_windows(prog: &Program, force: bool, per_cpu: bool) -> Result<()> {
+    let cpus = if per_cpu {
+        (0..libbpf_rs::num_possible_cpus()? as u32)
+            .map(Some)
+            .collect()
+    } else {
+        vec![None]
+    };
+    for cpu in cpus {
+        match bpf_prog::test_run_raw_tp(prog, &[force as u64], cpu) {
+            Ok(0) => {}
+            Ok(_) => log::warn!("Failed to flush a window; results were dropped"),
+            // Possible but offline CPU
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {}
+            Err(e) => return Err(e).context("Failed to run the flush program"),
+        }
+    }
+    Ok(())
+}
+
+/// Decoder of the ebql and opt probes' rows, which only carry the other group
+/// by fields and the optional aggregates if any of them is selected.
+fn pread_query_decoder(opts: &RunOptions) -> Decoder<PreadQueryRecord> {
+    PreadQueryRecord::decoder(PreadQueryRecord::wide_rows(opts.group_by, opts.aggs))
+}
+
+/// Configures a query that aggregates in the kernel: its window length, the
+/// ring buffer size it caps results to, and the size of its aggregation maps.
+/// The pread queries also take their group by fields and optional aggregates
+/// with `pread_query`, which also sizes their rows and the keys of their
+/// aggregation maps, and stage rows for the perf buffer per CPU. `percpu`
+/// takes only those fields and aggregates, as its windows are drained from
+/// maps keyed by window.
+macro_rules! configure_query {
+    ($open:ident, $opts:ident, [$($agg_map:ident),*], percpu) => {
+        $open.rodata_mut().GROUP_BY = $opts.group_by.bits();
+        $open.rodata_mut().AGGS = $opts.aggs.bits();
+        configure_query!(@maps $open, $opts, [$($agg_map),*]);
+    };
+    ($open:ident, $opts:ident, [$($agg_map:ident),*], pread_query) => {
+        $open.rodata_mut().GROUP_BY = $opts.group_by.bits();
+        $open.rodata_mut().AGGS = $opts.aggs.bits();
+        $open.rodata_mut().ROW_BYTES = pread_query_decoder($opts).record_size as u64;
+        $($open.maps_mut().$agg_map().set_key_size($opts.group_by.key_bytes())?;)*
+        if $opts.transport == Transport::Perfbuf {
+            $open
+                .maps_mut()
+                .perf_rows_pread_query()
+                .set_max_entries(libbpf_rs::num_possible_cpus()? as u32)?;
+        }
+        configure_query!($open, $opts, [$($agg_map),*]);
+    };
+    ($open:ident, $opts:ident, [$($agg_map:ident),*]) => {
+        if let Some(bytes) = $opts.ringbuf_bytes {
+            $open.rodata_mut().RINGBUF_BYTES = bytes as u64;
+        }
+        configure_query!(@maps $open, $opts, [$($agg_map),*]);
+    };
+    (@maps $open:ident, $opts:ident, [$($agg_map:ident),*]) => {
+        if let Some(window) = $opts.tumbling_window() {
+            $open.rodata_mut().WINDOW_NS = window.as_nanos() as u64;
+        }
+        if let Some(max_groups) = $opts.max_groups {
+            $($open.maps_mut().$agg_map().set_max_entries(max_groups)?;)*
+        }
+    };
+}
+
+skel_probe!(
+    EbqlProbe,
+    ebql::PreadQuerySkelBuilder,
+    ebql::OpenPreadQuerySkel<'static>,
+    ebql::PreadQuerySkel<'static>,
+    PreadQueryRecord,
+    ringbuf: ring_buf_pread_query,
+    capabilities: Capabilities {
+        group_by: true,
+        aggs: true,
+        windows: true,
+        perfbuf: true,
+        mappoll: true,
+        validate: Some(validate::validate::<EbqlProbe>),
+        ..Default::default()
+    },
+    consumer: |opts| {
+        FileGrouper::new(
+            opts.filter.process(),
+            opts.group_by,
+            opts.aggs,
+            opts.window.unwrap_or(WindowSpec::Tumbling(DEFAULT_WINDOW)),
+        )
+    },
+    configure: |open, opts| {
+        configure_query!(
+            open,
+            opts,
+            [
+                count__pread_query,
+                max_count_pread_query,
+                avg_count_pread_query,
+                ext_count_pread_query,
+                count__pread_query_1,
+                max_count_pread_query_1,
+                avg_count_pread_query_1,
+                ext_count_pread_query_1
+            ],
+            pread_query
+        );
+        // Values of enum WINDOW_KIND in the window header
+        let rodata = open.rodata_mut();
+        match opts.window {
+            Some(WindowSpec::Sliding { step, .. }) => rodata.WINDOW_NS = step.as_nanos() as u64,
+            Some(WindowSpec::Count { step, .. }) => {
+                rodata.WINDOW_KIND = 1;
+                rodata.WINDOW_COUNT = step;
+            }
+            Some(WindowSpec::Session { gap }) => {
+                rodata.WINDOW_KIND = 2;
+                rodata.SESSION_GAP_NS = gap.as_nanos() as u64;
+            }
+            Some(WindowSpec::Tumbling(_)) | None => {}
+        }
+    },
+    decoder: |opts| pread_query_decoder(opts),
+    flush: flush_pread_query,
+    per_cpu: false,
+    drain: mappoll::drain_ebql,
+    state: mappoll::EbqlWindows
+);
+
+skel_probe!(
+    OptProbe,
+    opt::PreadQueryNextSkelBuilder,
+    opt::OpenPreadQueryNextSkel<'static>,
+    opt::PreadQueryNextSkel<'static>,
+    PreadQueryRecord,
+    ringbuf: ring_buf_pread_query,
+    capabilities: Capabilities {
+        group_by: true,
+        aggs: true,
+        perfbuf: true,
+        validate: Some(validate::validate::<OptProbe>),
+        ..Default::default()
+    },
+    consumer: |opts| {
+        FileGrouper::new(
+            opts.filter.process(),
+            opts.group_by,
+            opts.aggs,
+            opts.window.unwrap_or(WindowSpec::Tumbling(DEFAULT_WINDOW)),
+        )
+    },
+    configure: |open, opts| {
+        configure_query!(
+            open,
+            opts,
+            [aggs_pread_query, ext_aggs_pread_query],
+            pread_query
+        );
+    },
+    decoder: |opts| pread_query_decoder(opts),
+    flush: flush_pread_query,
+    per_cpu: true
+);
+
+// Per-CPU maps keyed by window, with no window state in the kernel: every
+// pread goes into its CPU's copy of its (window, group) entry, and the windows
+// are only ever drained from the map.
+skel_probe!(
+    PercpuProbe,
+    percpu::PreadQuerySkelBuilder,
+    percpu::OpenPreadQuerySkel<'static>,
+    percpu::PreadQuerySkel<'static>,
+    PreadQueryRecord,
+    capabilities: Capabilities {
+        group_by: true,
+        aggs: true,
+        mappoll: true,
+        mappoll_only: true,
+        ..Default::default()
+    },
+    consumer: |opts| {
+        FileGrouper::new(
+            opts.filter.process(),
+            opts.group_by,
+            opts.aggs,
+            opts.window.unwrap_or(WindowSpec::Tumbling(DEFAULT_WINDOW)),
+        )
+    },
+    configure: |open, opts| {
+        configure_query!(open, opts, [aggs_pread_query], percpu);
+    },
+    drain: mappoll::drain_percpu,
+    state: mappoll::PercpuWindows
+);
+
+// Aggregates in userspace, so only the ring buffer size applies to the
+// skeleton.
+skel_probe!(
+    UnoptProbe,
+    unopt::PreadQuerySkelBuilder,
+    unopt::OpenPreadQuerySkel<'static>,
+    unopt::PreadQuerySkel<'static>,
+    RawPreadRecord,
+    ringbuf: ring_buf_pread_query,
+    // Computes every optional aggregate in userspace
+    capabilities: Capabilities {
+        aggs: true,
+        windows: true,
+        perfbuf: true,
+        ..Default::default()
+    },
+    consumer: |opts| {
+        UnoptAggregator::new(
+            opts.window.unwrap_or(WindowSpec::Tumbling(DEFAULT_WINDOW)),
+            opts.filter.process(),
+            opts.aggs,
+        )
+    },
+    configure: |_open, _opts| {}
+);
+
+skel_probe!(
+    LatencyProbe,
+    latency::PreadLatencySkelBuilder,
+    latency::OpenPreadLatencySkel<'static>,
+    latency::PreadLatencySkel<'static>,
+    PreadLatencyRecord,
+    ringbuf: ring_buf_pread_latency,
+    consumer: |_opts| LatencyHistogram::default(),
+    configure: |open, opts| {
+        configure_query!(open, opts, [aggs_pread_latency]);
+    }
+);
+
+// One program per syscall, named `sys_<syscall>`; only the selected ones are
+// loaded.
+skel_probe!(
+    SyscallProbe,
+    syscall::SyscallQuerySkelBuilder,
+    syscall::OpenSyscallQuerySkel<'static>,
+    syscall::SyscallQuerySkel<'static>,
+    SyscallQueryRecord,
+    ringbuf: ring_buf_syscall_query,
+    consumer: |_opts| SyscallTotals::default(),
+    configure: |open, opts| {
+        if opts.syscalls.is_empty() {
+            bail!("No syscalls selected for the syscall probe");
+        }
+        configure_query!(open, opts, [aggs_syscall_query]);
+        for prog in open.obj.progs_iter_mut() {
+            let name = prog.name()?;
+            let selected = opts.syscalls.iter().any(|s| name == format!("sys_{s}"));
+            prog.set_autoload(selected)?;
+        }
+    }
+);
+
+/// All probe variants selectable with `--probe-type`.
+pub fn registry() -> ProbeRegistry {
+    let mut registry = ProbeRegistry::new();
+    registry
+        .register::<EbqlProbe>(
+            "ebql",
+            "eBQL-generated query; per-aggregate maps and window state shared by all CPUs",
+        )
+        .register::<OptProbe>(
+            "opt",
+            "hand-optimized query; single per-CPU aggregation map keyed by fd",
+        )
+        .register::<PercpuProbe>(
+            "percpu",
+            "eBQL query on per-CPU maps keyed by window; merged across CPUs in userspace",
+        )
+        .register::<UnoptProbe>(
+            "unopt",
+            "submits every raw pread; aggregation happens in userspace",
+        )
+        .register::<LatencyProbe>(
+            "latency",
+            "pairs pread entry and exit; latency histogram and bytes read per fd",
+        )
+        .register::<SyscallProbe>(
+            "syscall",
+            "pread query over the I/O syscalls chosen with --syscalls, grouped by syscall too",
+        );
+    registry
+}
@@ -0,0 +1,97 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use common::{
+    bpf_prog,
+    bpf_structs::PreadQueryRecord,
+    poller::Poller,
+    probe::{Probe, ProbeRunner, RunOptions},
+    validate::{kernel_windows, ReferenceQuery, Validation},
+};
+use crossbeam::channel;
+
+use super::{UnoptProbe, DEFAULT_WINDOW};
+
+/// Runs the in-kernel probe `P` alongside the unopt probe until `done` is set,
+/// feeds the raw preads through [`ReferenceQuery`] and compares its windows
+/// against `P`'s.
+///
+/// The unopt output buffer drops records when userspace falls behind, which
+/// shows up as count mismatches; validate under moderate load.
+pub fn validate<P>(done: Arc<AtomicBool>, opts: &RunOptions, slack: u64) -> Result<Validation>
+where
+    P: Probe<Record = PreadQueryRecord> + Default,
+{
+    let mut kernel = P::default();
+    let mut raw = UnoptProbe::default();
+    kernel.open()?;
+    raw.open()?;
+    kernel.configure(opts)?;
+    raw.configure(opts)?;
+    kernel.load()?;
+    raw.load()?;
+
+    let (kernel_tx, kernel_rx) = channel::bounded(1024);
+    let (raw_tx, raw_rx) = channel::bounded(1024);
+    let kernel_rb = kernel
+        .output(Box::new(bpf_prog::create_event_handler(
+            kernel.decoder().decode,
+            kernel_tx,
+        )))?
+        .context("Validated probe has no output buffer")?;
+    let raw_rb = raw
+        .output(Box::new(bpf_prog::create_event_handler(
+            raw.decoder().decode,
+            raw_tx,
+        )))?
+        .context("Unopt probe has no output buffer")?;
+    raw.attach()?;
+    kernel.attach()?;
+
+    let poller = Poller::spawn(vec![kernel_rb, raw_rb])?;
+
+    // Receive until the poller has shut down and dropped both buffers, which
+    // closes the channels; a closed channel is swapped for one that never
+    // fires.
+    let mut kernel_rows = vec![];
+    let mut reference = ReferenceQuery::new(opts.tumbling_window().unwrap_or(DEFAULT_WINDOW));
+    let (mut kernel_rx, mut raw_rx) = (kernel_rx, raw_rx);
+    let (mut kernel_open, mut raw_open) = (true, true);
+    let mut shutdown = false;
+    while kernel_open || raw_open {
+        if !shutdown && done.load(SeqCst) {
+            poller.shutdown()?;
+            shutdown = true;
+        }
+        channel::select! {
+            recv(kernel_rx) -> records => match records {
+                Ok(records) => kernel_rows.extend(records),
+                Err(_) => {
+                    kernel_rx = channel::never();
+                    kernel_open = false;
+                }
+            },
+            recv(raw_rx) -> records => match records {
+                Ok(records) => records.iter().for_each(|r| reference.add(r)),
+                Err(_) => {
+                    raw_rx = channel::never();
+                    raw_open = false;
+                }
+            },
+            default(Duration::from_millis(100)) => {}
+        }
+    }
+    poller.join()?;
+
+    let kernel_windows = kernel_windows(kernel_rows);
+    let mut validation = Validation::compare(&kernel_windows, reference.windows(), slack);
+    validation.prog_stats = kernel.prog_stats()?;
+    validation.bpf_errors = kernel.bpf_errors()?;
+    Ok(validation)
+}
@@ -10,17 +10,18 @@ use std::{
         Arc,
     },
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use chrono::{DateTime, Utc};
 use clap::Parser;
-use common::prog_stats::ProgStats;
+use common::report::{self, Quantile, RunReport};
 use crossbeam::channel::{bounded, Receiver, Sender};
 use lazy_static::lazy_static;
 use rand::prelude::*;
 use request_stat::*;
 use rocksdb::{DBWithThreadMode, MultiThreaded, SingleThreaded, ThreadMode, WriteOptions};
+use serde::Serialize;
 
 static READ_QUERIES: AtomicUsize = AtomicUsize::new(0);
 static WRITE_QUERIES: AtomicUsize = AtomicUsize::new(0);
@@ -31,7 +32,7 @@ static DONE: AtomicBool = AtomicBool::new(false);
 // const DELAY_SECS: u64 = 10; // num secs to delay before starting, to warm up
 // cache
 
-#[derive(Parser, Debug, Clone)]
+#[derive(Parser, Debug, Clone, Serialize)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     #[arg(long)]
@@ -40,9 +41,14 @@ pub struct Args {
     #[arg(long)]
     quantiles_path: String,
 
+    /// Appends a run report to this path (JSON lines) and to `<path>.csv`
     #[arg(long, default_value_t = String::from(""))]
     stats_path: String,
 
+    /// Identifier recorded in the run report; defaults to start time and pid
+    #[arg(long, default_value_t = report::default_run_id())]
+    run_id: String,
+
     #[arg(long)]
     db_path: String,
 
@@ -73,13 +79,21 @@ fn init_counters(args: &Args) {
     thread::spawn(move || {
         let mut f = std::fs::File::create(out_path).unwrap();
         let mut counter = 0;
-        write!(f, "second,reads,writes,total\n").unwrap();
+        // timestamp_ms lines rows up with the probe's --bpf-samples-path output
+        write!(f, "second,reads,writes,total,timestamp_ms\n").unwrap();
         loop {
             counter += 1;
             let cur_read = READ_QUERIES.swap(0, SeqCst);
             let cur_write = WRITE_QUERIES.swap(0, SeqCst);
             let total = TOTAL.load(SeqCst);
-            let s = format!("{}, {}, {}, {}", counter, cur_read, cur_write, total);
+            let timestamp_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis();
+            let s = format!(
+                "{}, {}, {}, {}, {}",
+                counter, cur_read, cur_write, total, timestamp_ms
+            );
             println!("{}", s);
             write!(f, "{}\n", s).unwrap();
             thread::sleep(Duration::from_secs(1));
@@ -357,6 +371,7 @@ fn main() {
     let delay_secs = args.delay_secs;
     let quantile_path = args.quantiles_path.clone();
     let stats_path = args.stats_path.clone();
+    let report_args = args.clone();
 
     let (stats_tx, stats_rx) = bounded(1024);
     setup_workers_existing_db(db_path, args, stats_tx);
@@ -398,22 +413,34 @@ fn main() {
     )
     .unwrap();
 
-    for percentile in &percentiles {
-        let idx = (percentile * n) as usize;
-        println!("{}, {}", percentile, stats[idx].duration_secs);
+    let quantiles = percentiles
+        .iter()
+        .map(|&quantile| {
+            Quantile {
+                quantile,
+                latency_secs: stats[(quantile * n) as usize].duration_secs,
+            }
+        })
+        .collect::<Vec<_>>();
+    for q in &quantiles {
+        println!("{}, {}", q.quantile, q.latency_secs);
     }
 
-    let stat = ProgStats::get();
+    let mut report = RunReport::new(
+        "rocksdb-application",
+        report_args.run_id.clone(),
+        &report_args,
+    )
+    .unwrap();
+    let stat = &report.prog_stats;
     println!(
         "utime: {}\tstime: {}\tticks per second: {}\tProgram runtime: {}",
         stat.utime, stat.stime, stat.clock_tps, stat.runtime,
     );
+    report.counts.insert("ops", TOTAL.load(SeqCst) as u64);
+    report.counts.insert("read_samples", stats.len() as u64);
+    report.latency_quantiles = quantiles;
     if !stats_path.is_empty() {
-        let mut f = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(stats_path)
-            .unwrap();
-        write!(f, "{}\n", stat).unwrap();
+        report.write(&stats_path).unwrap();
     }
 }
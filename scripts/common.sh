@@ -18,6 +18,10 @@ APP_PID=0
 
 EBPF_PROBE_STDOUT="$OUT_DIR/probe-stdout"
 PROBE_STATS="$OUT_DIR/probe-stats"
+PROBE_BPF_SAMPLES="$OUT_DIR/probe-bpf-samples"
+# Extra probe filter flags, e.g. "--cgroup rocksdb" to only count the
+# application's preads (see `ebpf-probe-pread --help`)
+PROBE_FILTER=""
 EBPF_PROBE_PID=0
 
 function rocksdb_application_setup {
@@ -67,7 +71,7 @@ function rocksdb_application {
   echo "  Running on cores $CORE_LIST"
   echo "  Writing throughput to $THROUGHPUT_FILE"
   echo "  Writing quantiles to $QUANTILES_FILE"
-  echo "  Writing stats to $STATS_FILE"
+  echo "  Writing stats to $STATS_FILE and $STATS_FILE.csv"
 }
 
 function ebpf_probe {
@@ -76,6 +80,8 @@ function ebpf_probe {
   PROBE_CMD="/home/rtang/dev/ebql-benchmarks/target/release/ebpf-probe-pread"
   PROBE_CMD="$PROBE_CMD --probe-type $PROGRAM"
   PROBE_CMD="$PROBE_CMD --stats-path $STATS_FILE"
+  PROBE_CMD="$PROBE_CMD --bpf-samples-path $PROBE_BPF_SAMPLES-$PROGRAM"
+  PROBE_CMD="$PROBE_CMD $PROBE_FILTER"
 
   PROBE_CMD="taskset -c $CORE_LIST $PROBE_CMD"
 
@@ -87,11 +93,13 @@ function ebpf_probe {
   # echo "$EBPF_PROBE_PID"
   # EBPF_PROBE_PID=$(ps -o pid= --ppid $EBPF_PROBE_PID)
   echo "eBQL Probe PID: $EBPF_PROBE_PID"
-  echo "  Writing stats to $STATS_FILE and $STATS_FILE-bpf"
+  echo "  Writing stats to $STATS_FILE and $STATS_FILE.csv"
+  echo "  Writing BPF samples to $PROBE_BPF_SAMPLES-$PROGRAM"
 }
 
+# Also removes the numbered CSVs (<stats>.N.csv) that rows go to once the
+# report's columns change
 function clear_stats {
-  sudo echo > "$APP_STATS-$PROGRAM"
-  sudo echo > "$PROBE_STATS-$PROGRAM"
-  sudo echo > "$PROBE_STATS-$PROGRAM-bpf"
+  sudo rm -f "$APP_STATS-$PROGRAM" "$APP_STATS-$PROGRAM.csv" "$APP_STATS-$PROGRAM".*.csv
+  sudo rm -f "$PROBE_STATS-$PROGRAM" "$PROBE_STATS-$PROGRAM.csv" "$PROBE_STATS-$PROGRAM".*.csv
 }
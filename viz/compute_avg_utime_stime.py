@@ -1,3 +1,4 @@
+import glob
 import statistics
 import pandas as pd
 import sys
@@ -7,10 +8,14 @@ def compute_statistics(file_path):
     utimes = []
     stimes = []
 
-    data = pd.read_csv(file_path, header=None)
+    # Run report CSV written by --stats-path (<path>.csv), along with the
+    # numbered CSVs (<path>.N.csv) rows go to once the report's columns change
+    stem = file_path.removesuffix(".csv")
+    paths = [file_path] + sorted(glob.glob(glob.escape(stem) + ".[0-9]*.csv"))
+    data = pd.concat([pd.read_csv(path) for path in paths], ignore_index=True)
     # print(data)
-    utimes = data.iloc[:, 0].astype(int)
-    stimes = data.iloc[:, 1].astype(int)
+    utimes = data["utime"].astype(int)
+    stimes = data["stime"].astype(int)
 
     # Calculate mean and standard deviation for utime and stime
     utime_mean = statistics.mean(utimes)
@@ -25,7 +30,7 @@ def compute_statistics(file_path):
     print(f"Standard deviation of stime: {stime_std_dev}")
 
 if len(sys.argv) != 2:
-    print("Usage: python3 script_name.py path_to_data")
+    print("Usage: python3 script_name.py path_to_stats_csv")
     sys.exit(1)
 
 # Path to your data file